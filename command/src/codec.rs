@@ -0,0 +1,375 @@
+use crate::{Parameter, Parameters, Request, Statement};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+// the first byte of an entry payload is the version of its format, so the format can change without breaking
+// old logs. Requests and batches share this tag space: a new version of either one takes the next free tag,
+// and is added to the versions it's decoded from below.
+// requests with positional parameters only, written before named parameters
+const REQUEST_FORMAT_V1: u8 = 1;
+// version of a batch, which packs several requests in a single entry
+pub const BATCH_FORMAT_VERSION: u8 = 2;
+// version of the binary format of a Request stored in the Raft log
+pub const REQUEST_FORMAT_VERSION: u8 = 3;
+
+// tags of the payloads decoded as a request, and as a batch
+const REQUEST_FORMAT_VERSIONS: [u8; 2] = [REQUEST_FORMAT_V1, REQUEST_FORMAT_VERSION];
+const BATCH_FORMAT_VERSIONS: [u8; 1] = [BATCH_FORMAT_VERSION];
+
+// kinds of the parameters of a statement
+const POSITIONAL_PARAMETERS: u8 = 0;
+const NAMED_PARAMETERS: u8 = 1;
+
+// tags of the parameter types
+const PARAM_INTEGER: u8 = 1;
+const PARAM_REAL: u8 = 2;
+const PARAM_TEXT: u8 = 3;
+const PARAM_NULL: u8 = 4;
+const PARAM_BLOB: u8 = 5;
+const PARAM_BOOL: u8 = 6;
+
+// layout of a version 3 payload, all integers are little endian:
+//   version: u8 | transaction: u8 | statement count: u32 | statements
+// a statement is
+//   sql length: u32 | sql | kind: u8 | parameter count: u32 | parameters
+// a named parameter is prefixed by its length prefixed name.
+// a parameter is a type tag followed by an i64, the bits of a f64, a length prefixed utf-8 text,
+// nothing for a null, length prefixed bytes for a blob or a u8 for a bool.
+// A version 1 statement has no kind, its parameters are positional
+impl Request {
+    // encodes the request as the payload of a Raft log entry
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.push(REQUEST_FORMAT_VERSION);
+        buf.push(self.transaction as u8);
+        put_u32(&mut buf, self.statements.len() as u32);
+        for stmt in self.statements.iter() {
+            put_bytes(&mut buf, stmt.sql.as_bytes());
+            match &stmt.parameters {
+                Parameters::Positional(params) => {
+                    buf.push(POSITIONAL_PARAMETERS);
+                    put_u32(&mut buf, params.len() as u32);
+                    for param in params.iter() {
+                        put_parameter(&mut buf, param);
+                    }
+                }
+                Parameters::Named(params) => {
+                    buf.push(NAMED_PARAMETERS);
+                    put_u32(&mut buf, params.len() as u32);
+                    for (name, param) in params.iter() {
+                        put_bytes(&mut buf, name.as_bytes());
+                        put_parameter(&mut buf, param);
+                    }
+                }
+            }
+        }
+        buf
+    }
+
+    // decodes the payload of a Raft log entry
+    pub fn decode(data: &[u8]) -> Result<Request, String> {
+        let mut reader = Reader { buf: data };
+        let version = reader.u8()?;
+        if !REQUEST_FORMAT_VERSIONS.contains(&version) {
+            return Err(format!("unsupported request format version: {}", version));
+        }
+        let transaction = match reader.u8()? {
+            0 => false,
+            1 => true,
+            v => return Err(format!("invalid transaction flag: {}", v)),
+        };
+
+        let count = reader.u32()?;
+        let mut statements = Vec::new();
+        for _ in 0..count {
+            let sql = reader.string()?;
+            let kind = if version == REQUEST_FORMAT_V1 { POSITIONAL_PARAMETERS } else { reader.u8()? };
+            let param_count = reader.u32()?;
+            let parameters = match kind {
+                POSITIONAL_PARAMETERS => {
+                    let mut parameters = Vec::new();
+                    for _ in 0..param_count {
+                        parameters.push(reader.parameter()?);
+                    }
+                    Parameters::Positional(parameters.into_boxed_slice())
+                }
+                NAMED_PARAMETERS => {
+                    let mut parameters = BTreeMap::new();
+                    for _ in 0..param_count {
+                        let name = reader.string()?;
+                        parameters.insert(name, reader.parameter()?);
+                    }
+                    Parameters::Named(parameters)
+                }
+                kind => return Err(format!("unknown parameters kind: {}", kind)),
+            };
+            statements.push(Statement { sql, parameters });
+        }
+
+        if !reader.buf.is_empty() {
+            return Err(format!("{} trailing bytes after request", reader.buf.len()));
+        }
+        Ok(Request { transaction, statements: statements.into_boxed_slice() })
+    }
+}
+
+// layout of a batch:
+//   version: u8 | request count: u32 | requests, each one prefixed by its length as a u32
+// RequestBatch builds the payload of an entry holding several requests, which are applied in order
+pub struct RequestBatch {
+    buf: Vec<u8>,
+    count: u32,
+}
+
+impl Default for RequestBatch {
+    // an empty batch is the header alone, the count is written again by encode
+    fn default() -> Self {
+        let mut buf = vec![BATCH_FORMAT_VERSION];
+        put_u32(&mut buf, 0);
+        RequestBatch { buf, count: 0 }
+    }
+}
+
+impl RequestBatch {
+    pub fn new() -> Self {
+        RequestBatch::default()
+    }
+
+    pub fn push(&mut self, req: &Request) {
+        put_bytes(&mut self.buf, &req.encode());
+        self.count += 1;
+    }
+
+    // number of requests in the batch
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    // size in bytes of the payload
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn encode(mut self) -> Vec<u8> {
+        self.buf[1..5].copy_from_slice(&self.count.to_le_bytes());
+        self.buf
+    }
+
+    // decodes the requests of an entry, which holds either a batch or a single request
+    pub fn decode(data: &[u8]) -> Result<Vec<Request>, String> {
+        match data.first() {
+            Some(version) if BATCH_FORMAT_VERSIONS.contains(version) => {}
+            _ => return Request::decode(data).map(|req| vec![req]),
+        }
+
+        let mut reader = Reader { buf: &data[1..] };
+        let count = reader.u32()?;
+        let mut requests = Vec::new();
+        for _ in 0..count {
+            let len = reader.u32()? as usize;
+            requests.push(Request::decode(reader.take(len)?)?);
+        }
+
+        if !reader.buf.is_empty() {
+            return Err(format!("{} trailing bytes after batch", reader.buf.len()));
+        }
+        Ok(requests)
+    }
+}
+
+fn put_parameter(buf: &mut Vec<u8>, param: &Parameter) {
+    match param {
+        Parameter::Integer(v) => {
+            buf.push(PARAM_INTEGER);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        Parameter::Real(v) => {
+            buf.push(PARAM_REAL);
+            buf.extend_from_slice(&v.to_bits().to_le_bytes());
+        }
+        Parameter::Text(v) => {
+            buf.push(PARAM_TEXT);
+            put_bytes(buf, v.as_bytes());
+        }
+        Parameter::Null => buf.push(PARAM_NULL),
+        Parameter::Blob(v) => {
+            buf.push(PARAM_BLOB);
+            put_bytes(buf, v);
+        }
+        Parameter::Bool(v) => {
+            buf.push(PARAM_BOOL);
+            buf.push(*v as u8);
+        }
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, v: &[u8]) {
+    put_u32(buf, v.len() as u32);
+    buf.extend_from_slice(v);
+}
+
+// Reader consumes a payload from the front
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() < n {
+            return Err(String::from("unexpected end of request"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn array(&mut self) -> Result<[u8; 8], String> {
+        Ok(self.take(8)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|err| err.to_string())
+    }
+
+    fn parameter(&mut self) -> Result<Parameter, String> {
+        let param = match self.u8()? {
+            PARAM_INTEGER => Parameter::Integer(i64::from_le_bytes(self.array()?)),
+            PARAM_REAL => Parameter::Real(f64::from_bits(u64::from_le_bytes(self.array()?))),
+            PARAM_TEXT => Parameter::Text(self.string()?),
+            PARAM_NULL => Parameter::Null,
+            PARAM_BLOB => Parameter::Blob(self.bytes()?.to_vec()),
+            PARAM_BOOL => match self.u8()? {
+                0 => Parameter::Bool(false),
+                1 => Parameter::Bool(true),
+                v => return Err(format!("invalid bool parameter: {}", v)),
+            },
+            tag => return Err(format!("unknown parameter type: {}", tag)),
+        };
+        Ok(param)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> Request {
+        Request {
+            transaction: true,
+            statements: Box::new([
+                Statement {
+                    sql: String::from("INSERT INTO foo(id, name, score) VALUES(?, ?, ?)"),
+                    parameters: Parameters::Positional(Box::new([
+                        Parameter::Integer(-7), Parameter::Text(String::from("fióna")), Parameter::Real(1.5),
+                    ])),
+                },
+                Statement {
+                    sql: String::from("UPDATE foo SET name = :name WHERE id = @id"),
+                    parameters: Parameters::Named(vec![
+                        (String::from(":name"), Parameter::Text(String::from("aoife"))),
+                        (String::from("@id"), Parameter::Integer(-7)),
+                    ].into_iter().collect()),
+                },
+                Statement {
+                    sql: String::from("INSERT INTO bar(a, b, c, d) VALUES(?, ?, ?, ?)"),
+                    parameters: Parameters::Positional(Box::new([
+                        Parameter::Null, Parameter::Blob(vec![0, 255, 16]), Parameter::Blob(vec![]), Parameter::Bool(true),
+                    ])),
+                },
+                Statement { sql: String::from("DELETE FROM foo"), parameters: Parameters::default() },
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let data = request().encode();
+        assert_eq!(data[0], REQUEST_FORMAT_VERSION);
+        let decoded = Request::decode(&data).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", request()));
+    }
+
+    #[test]
+    fn test_decode_v1() {
+        // INSERT INTO foo(id) VALUES(?) with the parameter 7
+        let mut data = vec![REQUEST_FORMAT_V1, 0];
+        put_u32(&mut data, 1);
+        put_bytes(&mut data, b"INSERT INTO foo(id) VALUES(?)");
+        put_u32(&mut data, 1);
+        data.push(PARAM_INTEGER);
+        data.extend_from_slice(&7i64.to_le_bytes());
+
+        let decoded = Request::decode(&data).unwrap();
+        assert_eq!(decoded.statements[0].sql, "INSERT INTO foo(id) VALUES(?)");
+        assert_eq!(
+            format!("{:?}", decoded.statements[0].parameters),
+            format!("{:?}", Parameters::Positional(Box::new([Parameter::Integer(7)])))
+        );
+    }
+
+    #[test]
+    fn test_batch_encode_decode() {
+        let mut batch = RequestBatch::new();
+        batch.push(&request());
+        batch.push(&Request { transaction: false, statements: Box::new([]) });
+        assert_eq!(batch.len(), 2);
+        let data = batch.encode();
+        assert_eq!(data[0], BATCH_FORMAT_VERSION);
+
+        let decoded = RequestBatch::decode(&data).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(format!("{:?}", decoded[0]), format!("{:?}", request()));
+        assert!(decoded[1].statements.is_empty());
+        assert_eq!(RequestBatch::decode(&data[..data.len() - 1]).unwrap_err(), "unexpected end of request");
+
+        // entries written before batching hold a single request
+        let decoded = RequestBatch::decode(&request().encode()).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", vec![request()]));
+    }
+
+    #[test]
+    fn test_format_versions() {
+        // a request is never taken for a batch, or the other way around
+        for version in REQUEST_FORMAT_VERSIONS.iter() {
+            assert!(!BATCH_FORMAT_VERSIONS.contains(version));
+        }
+        assert!(REQUEST_FORMAT_VERSIONS.contains(&request().encode()[0]));
+        assert!(BATCH_FORMAT_VERSIONS.contains(&RequestBatch::new().encode()[0]));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let data = request().encode();
+        assert_eq!(Request::decode(&data[..data.len() - 1]).unwrap_err(), "unexpected end of request");
+        assert_eq!(Request::decode(&[]).unwrap_err(), "unexpected end of request");
+
+        let mut unknown_version = data.clone();
+        unknown_version[0] = 2;
+        assert_eq!(Request::decode(&unknown_version).unwrap_err(), "unsupported request format version: 2");
+
+        let mut trailing = data;
+        trailing.push(0);
+        assert_eq!(Request::decode(&trailing).unwrap_err(), "1 trailing bytes after request");
+    }
+}
@@ -1,4 +1,9 @@
-use serde::{Deserialize, Serialize};
+mod codec;
+pub use crate::codec::*;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::str::FromStr;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ExecuteRequest {
@@ -8,25 +13,78 @@ pub struct ExecuteRequest {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct QueryRequest {
     pub request: Request,
+    #[serde(default)]
+    pub level: ConsistencyLevel,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// ConsistencyLevel is how up to date the rows of a query must be
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsistencyLevel {
+    // reads the local database, which may be arbitrarily stale
+    None,
+    // reads the local database if the node believes it is the leader
+    #[default]
+    Weak,
+    // sends the query through the Raft log, the node must be the leader
+    Strong,
+    // waits for the read index given by the leader, followers may serve the query too
+    Linearizable,
+}
+
+impl FromStr for ConsistencyLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ConsistencyLevel::None),
+            "weak" => Ok(ConsistencyLevel::Weak),
+            "strong" => Ok(ConsistencyLevel::Strong),
+            "linearizable" => Ok(ConsistencyLevel::Linearizable),
+            _ => Err(format!("unknown consistency level: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Request {
     pub transaction: bool,
     pub statements: Box<[Statement]>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Statement {
     pub sql: String,
-    pub parameters: Box<[Parameter]>,
+    pub parameters: Parameters,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Parameters of a statement, in JSON either a list bound to the `?` placeholders in order,
+// or an object bound by name, where the names are written as in the statement: {":name": {"Text": "fiona"}}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Parameters {
+    Positional(Box<[Parameter]>),
+    // placeholders such as :name, @name or $name
+    Named(BTreeMap<String, Parameter>),
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Parameters::Positional(Box::new([]))
+    }
+}
+
+// Parameter is bound to a `?` of a statement. In JSON, a blob is a base64 string: {"Blob":"AP8Q"}
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Parameter {
     Integer(i64),
     Real(f64),
     Text(String),
+    Null,
+    #[serde(serialize_with = "serialize_blob", deserialize_with = "deserialize_blob")]
+    Blob(Vec<u8>),
+    // stored by SQLite as the integer 0 or 1
+    Bool(bool),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -42,16 +100,40 @@ pub struct Response {
 
 #[derive(Debug, Deserialize, Serialize)]
 // Rows represents the outcome of an operation that returns query data.
-pub struct Rows<'a> {
+pub struct Rows {
     pub columns: Vec<String>,
     pub types: Vec<DataType>,
-    #[serde(borrow)]
-    pub values: Vec<Vec<Value<'a>>>,
+    pub values: Vec<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub error: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Rows {
+    // replaces the BLOB values by their text in the encoding, which is how they are written in JSON
+    pub fn encode_blobs(&mut self, encoding: BlobEncoding) {
+        for value in self.values.iter_mut().flatten() {
+            if let Value::Blob(data) = value {
+                *value = Value::Text(encoding.encode(data));
+            }
+        }
+    }
+
+    // reads back the BLOB values of rows decoded from JSON: the text values of the blob columns
+    pub fn decode_blobs(&mut self, encoding: BlobEncoding) -> Result<(), String> {
+        for row in self.values.iter_mut() {
+            for (value, data_type) in row.iter_mut().zip(self.types.iter()) {
+                if let (Value::Text(text), DataType::Blob) = (&value, data_type) {
+                    *value = Value::Blob(encoding.decode(text)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 #[serde(untagged)]
-pub enum Value<'a> {
+pub enum Value {
     /// The value is a `NULL` value.
     Null,
     /// The value is a signed integer.
@@ -60,11 +142,68 @@ pub enum Value<'a> {
     Real(f64),
     /// The value is a text string.
     Text(String),
-    /// The value is a blob of data
-    Blob(&'a [u8]),
+    /// The value is a blob of data, written in JSON as a base64 string
+    #[serde(serialize_with = "serialize_blob")]
+    Blob(Vec<u8>),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+fn serialize_blob<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BlobEncoding::default().encode(data))
+}
+
+fn deserialize_blob<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    BlobEncoding::default().decode(&s).map_err(serde::de::Error::custom)
+}
+
+// BlobEncoding is how BLOB values are written in JSON
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobEncoding {
+    // standard base64, with padding
+    #[default]
+    Base64,
+    // lowercase hex, 2 digits per byte
+    Hex,
+}
+
+impl FromStr for BlobEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "base64" => Ok(BlobEncoding::Base64),
+            "hex" => Ok(BlobEncoding::Hex),
+            _ => Err(format!("unknown blob encoding: {}", s)),
+        }
+    }
+}
+
+impl BlobEncoding {
+    pub fn encode(&self, data: &[u8]) -> String {
+        match self {
+            BlobEncoding::Base64 => base64::encode(data),
+            BlobEncoding::Hex => data.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+
+    pub fn decode(&self, s: &str) -> Result<Vec<u8>, String> {
+        match self {
+            BlobEncoding::Base64 => base64::decode(s).map_err(|err| format!("invalid base64 blob: {}", err)),
+            BlobEncoding::Hex => {
+                // from_str_radix alone would take a sign, e.g. "+f"
+                if s.len() % 2 == 1 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(format!("invalid hex blob: {}", s));
+                }
+                (0..s.len()).step_by(2)
+                    .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("invalid hex blob: {}", s)))
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum DataType {
     Null,
@@ -76,4 +215,82 @@ pub enum DataType {
 
 fn is_zero(num: &i64) -> bool {
     *num == 0
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob_encoding() {
+        let data = vec![0, 255, 16];
+        assert_eq!(BlobEncoding::Base64.encode(&data), "AP8Q");
+        assert_eq!(BlobEncoding::Hex.encode(&data), "00ff10");
+        assert_eq!(BlobEncoding::Base64.decode("AP8Q").unwrap(), data);
+        assert_eq!(BlobEncoding::Hex.decode("00FF10").unwrap(), data);
+        assert!(BlobEncoding::Base64.decode("AP8Q!").is_err());
+        assert!(BlobEncoding::Hex.decode("00f").is_err());
+        assert!(BlobEncoding::Hex.decode("0g").is_err());
+        assert!(BlobEncoding::Hex.decode("+f").is_err());
+        assert_eq!("hex".parse::<BlobEncoding>().unwrap(), BlobEncoding::Hex);
+        assert_eq!("binary".parse::<BlobEncoding>().unwrap_err(), "unknown blob encoding: binary");
+    }
+
+    #[test]
+    fn test_rows_blob_json() {
+        let mut rows = Rows {
+            columns: vec![String::from("id"), String::from("data")],
+            types: vec![DataType::Integer, DataType::Blob],
+            values: vec![vec![Value::Integer(1), Value::Blob(vec![0, 255, 16])]],
+            error: String::new(),
+        };
+        let json = r#"{"columns":["id","data"],"types":["integer","blob"],"values":[[1,"AP8Q"]]}"#;
+        assert_eq!(serde_json::to_string(&rows).unwrap(), json);
+
+        // a client reads the blobs back with the same encoding
+        let mut decoded: Rows = serde_json::from_str(json).unwrap();
+        assert!(decoded.decode_blobs(BlobEncoding::Base64).is_ok());
+        assert_eq!(decoded.values, rows.values);
+
+        rows.encode_blobs(BlobEncoding::Hex);
+        let json = serde_json::to_string(&rows).unwrap();
+        assert_eq!(json, r#"{"columns":["id","data"],"types":["integer","blob"],"values":[[1,"00ff10"]]}"#);
+        let mut decoded: Rows = serde_json::from_str(&json).unwrap();
+        assert!(decoded.decode_blobs(BlobEncoding::Hex).is_ok());
+        assert_eq!(decoded.values[0][1], Value::Blob(vec![0, 255, 16]));
+    }
+
+    #[test]
+    fn test_parameter_json() {
+        let parameters = vec![
+            Parameter::Integer(1),
+            Parameter::Text(String::from("AP8Q")),
+            Parameter::Null,
+            Parameter::Blob(vec![0, 255, 16]),
+            Parameter::Bool(false),
+        ];
+        let json = r#"[{"Integer":1},{"Text":"AP8Q"},"Null",{"Blob":"AP8Q"},{"Bool":false}]"#;
+        assert_eq!(serde_json::to_string(&parameters).unwrap(), json);
+
+        // a text and a blob with the same content are told apart by their type
+        let decoded: Vec<Parameter> = serde_json::from_str(json).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", parameters));
+        assert!(serde_json::from_str::<Parameter>(r#"{"Blob":"AP8Q!"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parameters_json() {
+        let json = r#"{"sql":"SELECT ?","parameters":[{"Integer":1}]}"#;
+        let stmt: Statement = serde_json::from_str(json).unwrap();
+        assert!(matches!(stmt.parameters, Parameters::Positional(ref p) if p.len() == 1));
+        assert_eq!(serde_json::to_string(&stmt).unwrap(), json);
+
+        let json = r#"{"sql":"SELECT :a, @b","parameters":{":a":{"Integer":1},"@b":"Null"}}"#;
+        let stmt: Statement = serde_json::from_str(json).unwrap();
+        match stmt.parameters {
+            Parameters::Named(ref p) => assert_eq!(p.keys().collect::<Vec<_>>(), vec![":a", "@b"]),
+            _ => panic!("expected named parameters"),
+        }
+        assert_eq!(serde_json::to_string(&stmt).unwrap(), json);
+    }
+}
@@ -1,13 +1,30 @@
 use rusqlite::{Connection, ToSql, Transaction};
+use rusqlite::backup::{Backup, StepResult};
 use std::ops::{Deref};
-use rusqlite::types::ValueRef;
+use std::time::Duration;
+use rusqlite::types::{Null, ValueRef};
 use std::str;
-use command::{Value, Rows, Request, Response, DataType, Parameter, Statement};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use tempfile::NamedTempFile;
+use command::{Value, Rows, Request, Response, DataType, Parameter, Parameters, Statement};
+use crate::Error;
 
 const FK_CHECKS: &str = "PRAGMA foreign_keys";
 const FK_CHECKS_ENABLED: &str = "PRAGMA foreign_keys=ON";
 const FK_CHECKS_DISABLED: &str = "PRAGMA foreign_keys=OFF";
 
+// the applied index of the node is kept in the database itself, written in the same transaction as the requests of an entry
+const APPLIED_INDEX_TABLE: &str = "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = '_dust_applied_index'";
+const APPLIED_INDEX: &str = "SELECT applied_index FROM _dust_applied_index WHERE id = 0";
+const CREATE_APPLIED_INDEX: &str =
+    "CREATE TABLE IF NOT EXISTS _dust_applied_index (id INTEGER NOT NULL PRIMARY KEY, applied_index INTEGER NOT NULL)";
+const SET_APPLIED_INDEX: &str = "INSERT OR REPLACE INTO _dust_applied_index(id, applied_index) VALUES(0, ?)";
+
+// number of pages copied by a single step of a snapshot, the database is only locked during a step
+const SNAPSHOT_PAGES_PER_STEP: i32 = 1024;
+
 // represents a connection that be naked or a transaction
 enum WrappedConnection<'a> {
     Transaction {
@@ -74,75 +91,57 @@ pub struct DB {
 
 impl DB {
     // opens a file-based database, creating it if it does not exist.
-    pub fn open(path: &str) -> Result<DB, String> {
+    pub fn open(path: &str) -> Result<DB, Error> {
         return DB::new(format_dsn(path, "").as_str());
     }
 
     // opens a file-based database, creating it if it does not exist.
-    pub fn open_with_dsn(path: &str, dsn: &str) -> Result<DB, String> {
+    pub fn open_with_dsn(path: &str, dsn: &str) -> Result<DB, Error> {
         return DB::new(format_dsn(path, dsn).as_str());
     }
 
     // opens an in-memory database
-    pub fn open_in_memory() -> Result<DB, String> {
+    pub fn open_in_memory() -> Result<DB, Error> {
         return DB::new(format_dsn(":memory:", "").as_str());
     }
 
     // opens an in-memory database
-    pub fn open_in_memory_with_dsn(dsn: &str) -> Result<DB, String> {
+    pub fn open_in_memory_with_dsn(dsn: &str) -> Result<DB, Error> {
         return DB::new(format_dsn(":memory:", dsn).as_str());
     }
 
-    fn new(path: &str) -> Result<DB, String> {
-        return match Connection::open(format_dsn(path, "")) {
-            Ok(conn) => Ok(DB { conn: Some(conn) }),
-            Err(err) => Err(sql_err(err))
-        };
+    fn new(path: &str) -> Result<DB, Error> {
+        let conn = Connection::open(format_dsn(path, ""))?;
+        Ok(DB { conn: Some(conn) })
     }
 
     // closes the underlying database connection.
-    pub fn close(mut self) -> Result<(), String> {
-        if let Some(conn) = self.conn.take() {
-            return match conn.close() {
-                Ok(_) => { Ok(()) }
-                Err((conn, err)) => {
-                    eprintln!("{}", err);
-                    self.conn = Some(conn);
-                    Err(String::from("cannot create db path"))
-                }
-            };
-        }
-
-        Err(String::from("db connection is already closed"))
+    pub fn close(mut self) -> Result<(), Error> {
+        let conn = self.conn.take().ok_or(Error::Closed)?;
+        conn.close().map_err(|(_, err)| Error::from(err))
     }
 
     // allows control of foreign key constraint checks.
-    pub fn enable_fk_constraints(&self, flag: bool) -> Result<(), String> {
+    pub fn enable_fk_constraints(&self, flag: bool) -> Result<(), Error> {
         let mut q = FK_CHECKS_ENABLED;
         if !flag {
             q = FK_CHECKS_DISABLED;
         }
 
-        return match self.get_conn().execute(q, []) {
-            Ok(_) => { Ok(()) }
-            Err(err) => Err(sql_err(err))
-        };
+        self.get_conn()?.execute(q, [])?;
+        Ok(())
     }
 
     // returns whether FK constraints are set or not.
-    pub fn fk_constraints(&self) -> Result<bool, String> {
-        let res: rusqlite::Result<i64> = self.get_conn().query_row(
-            FK_CHECKS, [], |r| r.get(0));
-        return match res {
-            Ok(check) => { Ok(check == 1) }
-            Err(err) => Err(sql_err(err))
-        };
+    pub fn fk_constraints(&self) -> Result<bool, Error> {
+        let check: i64 = self.get_conn()?.query_row(FK_CHECKS, [], |r| r.get(0))?;
+        Ok(check == 1)
     }
 
     // execute_string_stmt executes a single query that modifies the database.
     // This is primarily a convenience function.
-    pub fn execute_string_stmt(&mut self, query: &str) -> Result<Vec<Response>, String> {
-        let stmt = Statement { sql: query.parse().unwrap(), parameters: Box::new([]) };
+    pub fn execute_string_stmt(&mut self, query: &str) -> Result<Vec<Response>, Error> {
+        let stmt = Statement { sql: query.parse().unwrap(), parameters: Parameters::default() };
         let r = Request {
             transaction: false,
             statements: Box::new([stmt]),
@@ -151,51 +150,12 @@ impl DB {
     }
 
     // executes queries that modify the database.
-    pub fn execute(&mut self, req: &Request) -> Result<Vec<Response>, String> {
-        return match self._execute(req) {
-            Ok(results) => { Ok(results) }
-            Err(err) => { Err(sql_err(err)) }
-        };
-    }
-
-    // internal implementation of execute that returns rusqlite::Error
-    fn _execute(&mut self, req: &Request) -> Result<Vec<Response>, rusqlite::Error> {
+    // The error of a statement is reported in its response, the request fails only if the transaction does.
+    pub fn execute(&mut self, req: &Request) -> Result<Vec<Response>, Error> {
         let is_tx = req.transaction;
-        let conn = WrappedConnection::new(self.get_mut_conn(), is_tx)?;
-
-        let mut rollback = false;
-        let mut results = Vec::new();
+        let conn = WrappedConnection::new(self.get_mut_conn()?, is_tx)?;
 
-        for stmt in req.statements.deref() {
-            let sql = &stmt.sql;
-            if sql == "" {
-                continue;
-            }
-
-            let params = &parameters(&stmt.parameters)[..];
-            let rows_affected = conn.execute(sql, params);
-
-            if rows_affected.is_err() {
-                results.push(Response {
-                    last_insert_id: 0,
-                    rows_affected: 0,
-                    error: rows_affected.err().unwrap().to_string(),
-                });
-                if is_tx {
-                    rollback = true;
-                    // in transaction, not allow to execute more statements
-                    break;
-                }
-                continue;
-            }
-
-            let last_insert_id = conn.last_insert_rowid();
-            results.push(Response {
-                last_insert_id,
-                rows_affected: rows_affected.unwrap() as i64,
-                error: "".to_string(),
-            });
-        }
+        let (results, rollback) = execute_request(&conn, req);
 
         if is_tx {
             if rollback {
@@ -208,9 +168,33 @@ impl DB {
         Ok(results)
     }
 
+    // executes the requests of a Raft entry in order, and records index as the applied index, in a single
+    // transaction: after a crash the database holds either the entry and its index, or none of them.
+    // A request with the transaction flag runs in a savepoint, which is rolled back when one of its statements fails
+    pub fn apply(&mut self, index: u64, requests: &[Request]) -> Result<Vec<Vec<Response>>, Error> {
+        let mut tx = self.get_mut_conn()?.transaction()?;
+        let mut results = Vec::with_capacity(requests.len());
+        for req in requests {
+            if req.transaction {
+                let savepoint = tx.savepoint()?;
+                let (responses, rollback) = execute_request(&savepoint, req);
+                // a savepoint which isn't committed is rolled back once dropped
+                if !rollback {
+                    savepoint.commit()?;
+                }
+                results.push(responses);
+            } else {
+                results.push(execute_request(&tx, req).0);
+            }
+        }
+        write_applied_index(&tx, index)?;
+        tx.commit()?;
+        Ok(results)
+    }
+
     // executes a single query that return rows, but don't modify database.
-    pub fn query_string_stmt(&self, query: &str) -> Result<Vec<Rows>, String> {
-        let stmt = Statement { sql: query.parse().unwrap(), parameters: Box::new([]) };
+    pub fn query_string_stmt(&self, query: &str) -> Result<Vec<Rows>, Error> {
+        let stmt = Statement { sql: query.parse().unwrap(), parameters: Parameters::default() };
         let r = Request {
             transaction: false,
             statements: Box::new([stmt]),
@@ -220,16 +204,9 @@ impl DB {
     }
 
     // query executes queries that return rows, but don't modify the database.
-    pub fn query(&self, req: &Request) -> Result<Vec<Rows>, String> {
-        return match self._query(req) {
-            Ok(results) => { Ok(results) }
-            Err(err) => { Err(sql_err(err)) }
-        };
-    }
-
-    // internal implementation of query that returns rusqlite::Error
-    fn _query(&self, req: &Request) -> Result<Vec<Rows>, rusqlite::Error> {
-        let conn = self.get_conn();
+    // The rows don't borrow the database, so they outlive the lock of a shared database.
+    pub fn query(&self, req: &Request) -> Result<Vec<Rows>, Error> {
+        let conn = self.get_conn()?;
         let mut results = Vec::new();
         for stmt in req.statements.deref() {
             let sql = &stmt.sql;
@@ -237,66 +214,77 @@ impl DB {
                 continue;
             }
 
-            let mut columns = Vec::new();
-            let mut types = Vec::new();
-
-            // a closure (for capturing the columns variable)
-            // which maps from a single row under database to Vec<Value>: each index represents a single column of that row
-            let mapper = |row: &rusqlite::Row| -> rusqlite::Result<Vec<Value>> {
-                // get all column name and type once
-                if columns.is_empty() {
-                    columns = row.column_names().into_iter().map(|name| name.to_string()).collect();
-                    types = (0..row.column_count()).into_iter().map(|i| {
-                        return match row.get_ref_unwrap(i) {
-                            ValueRef::Null => { DataType::Null }
-                            ValueRef::Integer(_) => { DataType::Integer }
-                            ValueRef::Real(_) => { DataType::Real }
-                            ValueRef::Text(_) => { DataType::Text }
-                            ValueRef::Blob(_) => { DataType::Blob }
-                        };
-                    }).collect();
-                }
-
-                let values = (0..row.column_count())
-                    .into_iter()
-                    .map(|i| {
-                        return match row.get_ref_unwrap(i) {
-                            ValueRef::Null => { Value::Null }
-                            ValueRef::Integer(val) => { Value::Integer(val) }
-                            ValueRef::Real(val) => { Value::Real(val) }
-                            ValueRef::Text(val) => { Value::Text(str::from_utf8(val).unwrap().to_string()) }
-                            // TODO clone &[u8] array
-                            ValueRef::Blob(_) => { Value::Null }
-                        };
-                    })
-                    .collect();
-
-                Ok(values)
-            };
-
-            let params = &parameters(&stmt.parameters)[..];
-            let mut prepare_stmt = conn.prepare(sql)?;
-            let rows = prepare_stmt.query_map(params, mapper)?;
-            let values = rows.into_iter().map(|r| r.unwrap()).collect();
-
-            results.push(Rows {
-                columns,
-                types,
-                values,
-            });
+            // an error belongs to its statement only, so the remaining statements are still executed
+            match query_stmt(conn, stmt) {
+                Ok(rows) => results.push(rows),
+                Err(err) => results.push(Rows {
+                    columns: Vec::new(),
+                    types: Vec::new(),
+                    values: Vec::new(),
+                    error: err.to_string(),
+                }),
+            }
         }
 
         Ok(results)
     }
 
-    // return the Connection object. panic if not available (e.g.: closed the database connection)
-    fn get_conn(&self) -> &Connection {
-        self.conn.as_ref().unwrap()
+    // returns the content of the whole database, copied with the SQLite online backup API.
+    // Pages are copied by steps, so other connections can write to the database between two steps.
+    pub fn snapshot(&self) -> Result<Vec<u8>, Error> {
+        let file = NamedTempFile::new()?;
+        let mut dst = Connection::open(file.path())?;
+        {
+            let backup = Backup::new(self.get_conn()?, &mut dst)?;
+            backup.run_to_completion(SNAPSHOT_PAGES_PER_STEP, Duration::from_millis(0), None)?;
+        }
+        dst.close().map_err(|(_, err)| Error::from(err))?;
+        Ok(fs::read(file.path())?)
+    }
+
+    // replaces the content of the whole database by a snapshot.
+    // The snapshot is checked first, then copied in a single step: the database is either fully replaced or unchanged.
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(data)?;
+
+        let src = Connection::open(file.path())?;
+        let check: String = src.query_row("PRAGMA quick_check", [], |r| r.get(0))?;
+        if check != "ok" {
+            return Err(Error::InvalidSnapshot(check));
+        }
+
+        let backup = Backup::new(&src, self.get_mut_conn()?)?;
+        return match backup.step(-1)? {
+            StepResult::Done => Ok(()),
+            res => Err(Error::Other(format!("cannot restore snapshot: {:?}", res))),
+        };
+    }
+
+    // returns the index of the last Raft entry applied to the database, 0 if it was never set.
+    pub fn applied_index(&self) -> Result<u64, Error> {
+        let conn = self.get_conn()?;
+        let exists: i64 = conn.query_row(APPLIED_INDEX_TABLE, [], |r| r.get(0))?;
+        if exists == 0 {
+            return Ok(0);
+        }
+        let index: i64 = conn.query_row(APPLIED_INDEX, [], |r| r.get(0))?;
+        Ok(index as u64)
     }
 
-    // return the Connection object which can be mutable. panic if not available (e.g.: closed the database connection)
-    fn get_mut_conn(&mut self) -> &mut Connection {
-        self.conn.as_mut().unwrap()
+    // records the index of the last Raft entry applied to the database.
+    pub fn set_applied_index(&mut self, index: u64) -> Result<(), Error> {
+        write_applied_index(self.get_conn()?, index)
+    }
+
+    // return the Connection object, or an error once the database connection is closed
+    fn get_conn(&self) -> Result<&Connection, Error> {
+        self.conn.as_ref().ok_or(Error::Closed)
+    }
+
+    // return the Connection object which can be mutable, or an error once the database connection is closed
+    fn get_mut_conn(&mut self) -> Result<&mut Connection, Error> {
+        self.conn.as_mut().ok_or(Error::Closed)
     }
 }
 
@@ -308,26 +296,161 @@ fn format_dsn(path: &str, dsn: &str) -> String {
     return path.to_string();
 }
 
+// executes the statements of a request, and tells whether its transaction must be rolled back:
+// in a transaction, the statements after a failed one aren't executed
+fn execute_request(conn: &Connection, req: &Request) -> (Vec<Response>, bool) {
+    let mut results = Vec::new();
+
+    for stmt in req.statements.deref() {
+        let sql = &stmt.sql;
+        if sql == "" {
+            continue;
+        }
+
+        let rows_affected = execute_stmt(conn, stmt);
+
+        if rows_affected.is_err() {
+            results.push(Response {
+                last_insert_id: 0,
+                rows_affected: 0,
+                error: rows_affected.err().unwrap().to_string(),
+            });
+            if req.transaction {
+                // in transaction, not allow to execute more statements
+                return (results, true);
+            }
+            continue;
+        }
+
+        let last_insert_id = conn.last_insert_rowid();
+        results.push(Response {
+            last_insert_id,
+            rows_affected: rows_affected.unwrap() as i64,
+            error: "".to_string(),
+        });
+    }
+
+    (results, false)
+}
+
+fn write_applied_index(conn: &Connection, index: u64) -> Result<(), Error> {
+    conn.execute(CREATE_APPLIED_INDEX, [])?;
+    conn.execute(SET_APPLIED_INDEX, [index as i64])?;
+    Ok(())
+}
+
+// executes a single statement that modifies the database
+fn execute_stmt(conn: &Connection, stmt: &Statement) -> Result<usize, Error> {
+    let mut prepare_stmt = conn.prepare(&stmt.sql)?;
+    let rows_affected = match &stmt.parameters {
+        Parameters::Positional(params) => prepare_stmt.execute(&parameters(params)[..])?,
+        Parameters::Named(params) => prepare_stmt.execute(&named_parameters(&prepare_stmt, params)?[..])?,
+    };
+    Ok(rows_affected)
+}
+
+// executes a single statement that returns rows
+fn query_stmt(conn: &Connection, stmt: &Statement) -> Result<Rows, Error> {
+    let mut columns = Vec::new();
+    let mut types = Vec::new();
+
+    // a closure (for capturing the columns variable)
+    // which maps from a single row under database to Vec<Value>: each index represents a single column of that row
+    let mapper = |row: &rusqlite::Row| -> rusqlite::Result<Vec<Value>> {
+        // get all column name and type once
+        if columns.is_empty() {
+            columns = row.column_names().into_iter().map(|name| name.to_string()).collect();
+            types = (0..row.column_count()).into_iter().map(|i| {
+                return match row.get_ref_unwrap(i) {
+                    ValueRef::Null => { DataType::Null }
+                    ValueRef::Integer(_) => { DataType::Integer }
+                    ValueRef::Real(_) => { DataType::Real }
+                    ValueRef::Text(_) => { DataType::Text }
+                    ValueRef::Blob(_) => { DataType::Blob }
+                };
+            }).collect();
+        }
+
+        // SQLite doesn't check the encoding of TEXT values, invalid UTF-8 fails the statement
+        (0..row.column_count())
+            .into_iter()
+            .map(|i| {
+                return match row.get_ref_unwrap(i) {
+                    ValueRef::Null => { Ok(Value::Null) }
+                    ValueRef::Integer(val) => { Ok(Value::Integer(val)) }
+                    ValueRef::Real(val) => { Ok(Value::Real(val)) }
+                    ValueRef::Text(val) => {
+                        str::from_utf8(val).map(|val| Value::Text(val.to_string())).map_err(rusqlite::Error::Utf8Error)
+                    }
+                    ValueRef::Blob(val) => { Ok(Value::Blob(val.to_vec())) }
+                };
+            })
+            .collect()
+    };
+
+    let mut prepare_stmt = conn.prepare(&stmt.sql)?;
+    let rows = match &stmt.parameters {
+        Parameters::Positional(params) => prepare_stmt.query_map(&parameters(params)[..], mapper),
+        Parameters::Named(params) => {
+            let params = named_parameters(&prepare_stmt, params)?;
+            prepare_stmt.query_map(&params[..], mapper)
+        }
+    };
+    let values = rows.and_then(|rows| rows.collect::<Result<Vec<_>, _>>())?;
+
+    Ok(Rows {
+        columns,
+        types,
+        values,
+        error: "".to_string(),
+    })
+}
+
 // convert parameters to the suitable format for rustqlite
-fn parameters(parameters: &Box<[Parameter]>) -> Vec<&dyn ToSql> {
-    let params: Vec<&dyn ToSql> = parameters.iter().map(|p| {
-        return match p {
-            Parameter::Integer(x) => { x as &dyn ToSql }
-            Parameter::Real(x) => { x as &dyn ToSql }
-            Parameter::Text(x) => { x as &dyn ToSql }
-        };
-    }).collect();
+fn parameters(parameters: &[Parameter]) -> Vec<&dyn ToSql> {
+    let params: Vec<&dyn ToSql> = parameters.iter().map(parameter).collect();
     return params;
 }
 
-fn sql_err(err: rusqlite::Error) -> String {
-    eprintln!("rusqlite exception -- {:?}", err);
-    err.to_string()
+fn parameter(p: &Parameter) -> &dyn ToSql {
+    return match p {
+        Parameter::Integer(x) => { x as &dyn ToSql }
+        Parameter::Real(x) => { x as &dyn ToSql }
+        Parameter::Text(x) => { x as &dyn ToSql }
+        Parameter::Null => { &Null as &dyn ToSql }
+        Parameter::Blob(x) => { x as &dyn ToSql }
+        Parameter::Bool(x) => { x as &dyn ToSql }
+    };
+}
+
+// pairs the named parameters with the placeholders of the statement.
+// SQLite binds NULL to a placeholder without parameter, so every placeholder must be given one
+fn named_parameters<'a>(
+    stmt: &rusqlite::Statement,
+    parameters: &'a BTreeMap<String, Parameter>,
+) -> Result<Vec<(&'a str, &'a dyn ToSql)>, Error> {
+    for i in 1..stmt.parameter_count() + 1 {
+        match stmt.parameter_name(i) {
+            Some(name) if parameters.contains_key(name) => {}
+            Some(name) => return Err(Error::Parameter(format!("missing parameter: {}", name))),
+            None => return Err(Error::Parameter(format!("missing parameter: ?{}", i))),
+        }
+    }
+
+    let mut params = Vec::new();
+    for (name, param) in parameters.iter() {
+        if stmt.parameter_index(name)?.is_none() {
+            return Err(Error::Parameter(format!("unknown parameter: {}", name)));
+        }
+        params.push((name.as_str(), parameter(param)));
+    }
+    Ok(params)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use command::BlobEncoding;
 
     #[test]
     fn test_initialise_db() {
@@ -344,7 +467,9 @@ mod tests {
         // test error when initialising db
         let res = DB::open("etc/sample.db");
         assert!(res.is_err());
-        assert_eq!(res.err().unwrap(), "unable to open database file: etc/sample.db")
+        let err = res.err().unwrap();
+        assert!(matches!(err, Error::CantOpen(_)));
+        assert_eq!(err.to_string(), "unable to open database file: etc/sample.db")
     }
 
     #[test]
@@ -472,9 +597,9 @@ mod tests {
         let req = &Request {
             transaction: false,
             statements: Box::new([
-                Statement { sql: r#"INSERT INTO "names" VALUES(1,'bob','123-45-678')"#.to_string(), parameters: Box::new([]) },
-                Statement { sql: r#"INSERT INTO "names" VALUES(2,'tom','111-22-333')"#.to_string(), parameters: Box::new([]) },
-                Statement { sql: r#"INSERT INTO "names" VALUES(3,'matt','222-22-333')"#.to_string(), parameters: Box::new([]) },
+                Statement { sql: r#"INSERT INTO "names" VALUES(1,'bob','123-45-678')"#.to_string(), parameters: Parameters::default() },
+                Statement { sql: r#"INSERT INTO "names" VALUES(2,'tom','111-22-333')"#.to_string(), parameters: Parameters::default() },
+                Statement { sql: r#"INSERT INTO "names" VALUES(3,'matt','222-22-333')"#.to_string(), parameters: Parameters::default() },
             ]),
         };
         assert!(db.execute(req).is_ok());
@@ -515,11 +640,11 @@ mod tests {
             statements: Box::new([
                 Statement {
                     sql: r#"INSERT INTO foo(name) VALUES("fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(name) VALUES("dana")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
             ]),
         };
@@ -533,8 +658,8 @@ mod tests {
         let req = &Request {
             transaction: false,
             statements: Box::new([
-                Statement { sql: r#"SELECT * FROM foo"#.to_string(), parameters: Box::new([]) },
-                Statement { sql: r#"SELECT * FROM foo"#.to_string(), parameters: Box::new([]) },
+                Statement { sql: r#"SELECT * FROM foo"#.to_string(), parameters: Parameters::default() },
+                Statement { sql: r#"SELECT * FROM foo"#.to_string(), parameters: Parameters::default() },
             ]),
         };
 
@@ -561,7 +686,7 @@ mod tests {
                 id INTEGER NOT NULL PRIMARY KEY,
                 name TEXT
                 )".to_string(),
-                parameters: Box::new([]),
+                parameters: Parameters::default(),
             }]),
         };
         assert!(db.execute(req).is_ok());
@@ -571,11 +696,11 @@ mod tests {
             statements: Box::new([
                 Statement {
                     sql: r#"INSERT INTO foo(name) VALUES("fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(name) VALUES("dana")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
             ]),
         };
@@ -597,11 +722,11 @@ mod tests {
             statements: Box::new([
                 Statement {
                     sql: "INSERT INTO foo(name, age, money) VALUES(?, ?, ?)".to_string(),
-                    parameters: Box::new([
+                    parameters: Parameters::Positional(Box::new([
                         Parameter::Text("fiona".to_string()),
                         Parameter::Integer(20),
                         Parameter::Real(100.75),
-                    ]),
+                    ])),
                 }
             ]),
         };
@@ -626,15 +751,15 @@ mod tests {
             statements: Box::new([
                 Statement {
                     sql: "INSERT INTO foo(name) VALUES(?)".to_string(),
-                    parameters: Box::new([
+                    parameters: Parameters::Positional(Box::new([
                         Parameter::Text("fiona".to_string()),
-                    ]),
+                    ])),
                 }
             ]),
         };
         assert!(db.execute(&req).is_ok());
 
-        req.statements[0].parameters[0] = Parameter::Text("aoife".to_string());
+        req.statements[0].parameters = Parameters::Positional(Box::new([Parameter::Text("aoife".to_string())]));
         assert!(db.execute(&req).is_ok());
 
         let r = db.query_string_stmt("SELECT * FROM foo");
@@ -645,7 +770,7 @@ mod tests {
         );
 
         req.statements[0].sql = "SELECT * FROM foo WHERE name=?".to_string();
-        req.statements[0].parameters[0] = Parameter::Text("aoife".to_string());
+        req.statements[0].parameters = Parameters::Positional(Box::new([Parameter::Text("aoife".to_string())]));
         let r = db.query(&req);
         assert!(r.is_ok());
         assert_eq!(
@@ -653,7 +778,7 @@ mod tests {
             serde_json::to_string(&r.unwrap()).unwrap()
         );
 
-        req.statements[0].parameters[0] = Parameter::Text("fiona".to_string());
+        req.statements[0].parameters = Parameters::Positional(Box::new([Parameter::Text("fiona".to_string())]));
         let r = db.query(&req);
         assert!(r.is_ok());
         assert_eq!(
@@ -666,15 +791,15 @@ mod tests {
             statements: Box::new([
                 Statement {
                     sql: "SELECT * FROM foo WHERE NAME=?".to_string(),
-                    parameters: Box::new([
+                    parameters: Parameters::Positional(Box::new([
                         Parameter::Text("fiona".to_string()),
-                    ]),
+                    ])),
                 },
                 Statement {
                     sql: "SELECT * FROM foo WHERE NAME=?".to_string(),
-                    parameters: Box::new([
+                    parameters: Parameters::Positional(Box::new([
                         Parameter::Text("aoife".to_string()),
-                    ]),
+                    ])),
                 },
             ]),
         };
@@ -697,19 +822,19 @@ mod tests {
             statements: Box::new([
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(1, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(2, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(3, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(4, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 }
             ]),
         };
@@ -740,19 +865,19 @@ mod tests {
             statements: Box::new([
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(1, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(2, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(1, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(4, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 }
             ]),
         };
@@ -776,7 +901,7 @@ mod tests {
             statements: Box::new([
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(1, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 }
             ]),
         };
@@ -801,19 +926,19 @@ mod tests {
             statements: Box::new([
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(1, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(2, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(1, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 },
                 Statement {
                     sql: r#"INSERT INTO foo(id, name) VALUES(4, "fiona")"#.to_string(),
-                    parameters: Box::new([]),
+                    parameters: Parameters::default(),
                 }
             ]),
         };
@@ -824,4 +949,226 @@ mod tests {
             serde_json::to_string(&r.unwrap()).unwrap()
         );
     }
+
+    #[test]
+    fn test_query_error_per_stmt() {
+        let mut db = DB::open_in_memory().unwrap();
+        assert!(db.execute_string_stmt("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, name TEXT)").is_ok());
+        assert!(db.execute_string_stmt(r#"INSERT INTO foo(name) VALUES("fiona")"#).is_ok());
+
+        let req = &Request {
+            transaction: false,
+            statements: Box::new([
+                Statement { sql: r#"SELECT * FROM bar"#.to_string(), parameters: Parameters::default() },
+                Statement { sql: r#"SELECT * FROM foo"#.to_string(), parameters: Parameters::default() },
+            ]),
+        };
+
+        let r = db.query(req);
+        assert!(r.is_ok());
+        assert_eq!(
+            r#"[{"columns":[],"types":[],"values":[],"error":"no such table: bar"},{"columns":["id","name"],"types":["integer","text"],"values":[[1,"fiona"]]}]"#,
+            serde_json::to_string(&r.unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_query_invalid_text() {
+        let db = DB::open_in_memory().unwrap();
+        let r = db.query_string_stmt("SELECT CAST(x'ff' AS TEXT)");
+        assert_eq!(
+            r#"[{"columns":[],"types":[],"values":[],"error":"invalid utf-8 sequence of 1 bytes from index 0"}]"#,
+            serde_json::to_string(&r.unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut db = DB::open_in_memory().unwrap();
+        assert!(db.execute_string_stmt("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, name TEXT)").is_ok());
+        assert!(db.execute_string_stmt(r#"INSERT INTO foo(name) VALUES("fiona")"#).is_ok());
+        let snapshot = db.snapshot().unwrap();
+
+        // changes after the snapshot are discarded when restoring it
+        assert!(db.execute_string_stmt(r#"INSERT INTO foo(name) VALUES("aoife")"#).is_ok());
+        let mut other = DB::open_in_memory().unwrap();
+        assert!(other.execute_string_stmt("CREATE TABLE bar (id INTEGER NOT NULL PRIMARY KEY)").is_ok());
+
+        for db in [&mut db, &mut other].iter_mut() {
+            assert!(db.restore(&snapshot).is_ok());
+            let r = db.query_string_stmt("SELECT * FROM foo");
+            assert_eq!(
+                r#"[{"columns":["id","name"],"types":["integer","text"],"values":[[1,"fiona"]]}]"#,
+                serde_json::to_string(&r.unwrap()).unwrap()
+            );
+        }
+
+        let r = other.query_string_stmt("SELECT * FROM bar");
+        assert_eq!(
+            r#"[{"columns":[],"types":[],"values":[],"error":"no such table: bar"}]"#,
+            serde_json::to_string(&r.unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_restore_invalid_snapshot() {
+        let mut db = DB::open_in_memory().unwrap();
+        assert!(db.execute_string_stmt("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, name TEXT)").is_ok());
+        assert!(db.execute_string_stmt(r#"INSERT INTO foo(name) VALUES("fiona")"#).is_ok());
+
+        // a damaged snapshot leaves the database untouched
+        let mut snapshot = db.snapshot().unwrap();
+        snapshot.truncate(snapshot.len() / 2);
+        snapshot[0] = b'X';
+        assert!(matches!(db.restore(&snapshot), Err(Error::Corrupt(_))));
+
+        let r = db.query_string_stmt("SELECT * FROM foo");
+        assert_eq!(
+            r#"[{"columns":["id","name"],"types":["integer","text"],"values":[[1,"fiona"]]}]"#,
+            serde_json::to_string(&r.unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_applied_index() {
+        let mut db = DB::open_in_memory().unwrap();
+        assert_eq!(db.applied_index().unwrap(), 0);
+        assert!(db.set_applied_index(3).is_ok());
+        assert!(db.set_applied_index(7).is_ok());
+        assert_eq!(db.applied_index().unwrap(), 7);
+
+        // the index is part of the snapshot
+        let mut other = DB::open_in_memory().unwrap();
+        assert!(other.restore(&db.snapshot().unwrap()).is_ok());
+        assert_eq!(other.applied_index().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_apply_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dust.db");
+        let mut db = DB::open(path.to_str().unwrap()).unwrap();
+        assert!(db.execute_string_stmt("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, name TEXT)").is_ok());
+
+        let insert = |name: &str| Statement {
+            sql: String::from("INSERT INTO foo(name) VALUES(?)"),
+            parameters: Parameters::Positional(Box::new([Parameter::Text(name.to_string())])),
+        };
+        let fail = Statement { sql: String::from("INSERT INTO foo(id) VALUES(1)"), parameters: Parameters::default() };
+        let requests = vec![
+            Request { transaction: false, statements: Box::new([insert("fiona"), fail.clone()]) },
+            // rolled back by itself, the other requests of the entry are kept
+            Request { transaction: true, statements: Box::new([insert("aoife"), fail]) },
+            Request { transaction: false, statements: Box::new([insert("declan")]) },
+        ];
+        let results = db.apply(5, &requests).unwrap();
+        assert_eq!(
+            r#"[[{"last_insert_id":1,"rows_affected":1},{"error":"UNIQUE constraint failed: foo.id"}],[{"last_insert_id":2,"rows_affected":1},{"error":"UNIQUE constraint failed: foo.id"}],[{"last_insert_id":2,"rows_affected":1}]]"#,
+            serde_json::to_string(&results).unwrap()
+        );
+
+        // the rows and the applied index are written together
+        drop(db);
+        let db = DB::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(db.applied_index().unwrap(), 5);
+        let r = db.query_string_stmt("SELECT name FROM foo ORDER BY id");
+        assert_eq!(
+            r#"[{"columns":["name"],"types":["text"],"values":[["fiona"],["declan"]]}]"#,
+            serde_json::to_string(&r.unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_blob_stmts() {
+        let mut db = DB::open_in_memory().unwrap();
+        assert!(db.execute_string_stmt("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, data BLOB)").is_ok());
+        assert!(db.execute_string_stmt("INSERT INTO foo(data) VALUES(X'00FF10')").is_ok());
+        assert!(db.execute_string_stmt("INSERT INTO foo(data) VALUES(X'')").is_ok());
+
+        let mut r = db.query_string_stmt("SELECT data FROM foo").unwrap();
+        assert_eq!(r[0].types, vec![DataType::Blob]);
+        assert_eq!(r[0].values, vec![vec![Value::Blob(vec![0, 255, 16])], vec![Value::Blob(vec![])]]);
+        assert_eq!(
+            r#"[{"columns":["data"],"types":["blob"],"values":[["AP8Q"],[""]]}]"#,
+            serde_json::to_string(&r).unwrap()
+        );
+
+        r[0].encode_blobs(BlobEncoding::Hex);
+        assert_eq!(
+            r#"[{"columns":["data"],"types":["blob"],"values":[["00ff10"],[""]]}]"#,
+            serde_json::to_string(&r).unwrap()
+        );
+
+        // the blobs read back are the bytes written
+        let r = db.query_string_stmt("SELECT hex(data) FROM foo WHERE id = 1").unwrap();
+        assert_eq!(r[0].values, vec![vec![Value::Text(String::from("00FF10"))]]);
+    }
+
+    #[test]
+    fn test_parameterized_null_blob_bool_stmts() {
+        let mut db = DB::open_in_memory().unwrap();
+        assert!(db.execute_string_stmt("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, name TEXT, data BLOB, active BOOLEAN)").is_ok());
+
+        let req = Request {
+            transaction: false,
+            statements: Box::new([Statement {
+                sql: String::from("INSERT INTO foo(name, data, active) VALUES(?, ?, ?)"),
+                parameters: Parameters::Positional(Box::new([Parameter::Null, Parameter::Blob(vec![0, 255, 16]), Parameter::Bool(true)])),
+            }]),
+        };
+        assert_eq!(db.execute(&req).unwrap()[0].error, "");
+
+        let req = Request {
+            transaction: false,
+            statements: Box::new([Statement {
+                sql: String::from("SELECT name, data, active FROM foo WHERE data = ? AND active = ?"),
+                parameters: Parameters::Positional(Box::new([Parameter::Blob(vec![0, 255, 16]), Parameter::Bool(true)])),
+            }]),
+        };
+        let r = db.query(&req).unwrap();
+        assert_eq!(r[0].types, vec![DataType::Null, DataType::Blob, DataType::Integer]);
+        assert_eq!(r[0].values, vec![vec![Value::Null, Value::Blob(vec![0, 255, 16]), Value::Integer(1)]]);
+    }
+
+    #[test]
+    fn test_named_parameterized_stmts() {
+        let mut db = DB::open_in_memory().unwrap();
+        assert!(db.execute_string_stmt("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, name TEXT, age INT)").is_ok());
+
+        let named = |sql: &str, params: Vec<(&str, Parameter)>| Statement {
+            sql: sql.to_string(),
+            parameters: Parameters::Named(params.into_iter().map(|(k, v)| (k.to_string(), v)).collect()),
+        };
+        let req = Request {
+            transaction: false,
+            statements: Box::new([
+                named("INSERT INTO foo(name, age) VALUES(:name, @age)",
+                      vec![(":name", Parameter::Text("fiona".to_string())), ("@age", Parameter::Integer(20))]),
+                named("INSERT INTO foo(name, age) VALUES($name, $age)",
+                      vec![("$name", Parameter::Text("aoife".to_string())), ("$age", Parameter::Integer(30))]),
+                // every placeholder must be given a parameter, and every parameter must have a placeholder
+                named("INSERT INTO foo(name, age) VALUES(:name, :age)", vec![(":name", Parameter::Text("dana".to_string()))]),
+                named("INSERT INTO foo(name) VALUES(:name)",
+                      vec![(":name", Parameter::Text("dana".to_string())), (":age", Parameter::Integer(40))]),
+                named("INSERT INTO foo(name) VALUES(?)", vec![]),
+            ]),
+        };
+        assert_eq!(
+            r#"[{"last_insert_id":1,"rows_affected":1},{"last_insert_id":2,"rows_affected":1},{"error":"missing parameter: :age"},{"error":"unknown parameter: :age"},{"error":"missing parameter: ?1"}]"#,
+            serde_json::to_string(&db.execute(&req).unwrap()).unwrap()
+        );
+
+        // a placeholder used twice takes a single parameter
+        let req = Request {
+            transaction: false,
+            statements: Box::new([
+                named("SELECT name FROM foo WHERE age > :age AND age < :age + 15", vec![(":age", Parameter::Integer(10))]),
+                named("SELECT name FROM foo WHERE age = :age", vec![]),
+            ]),
+        };
+        assert_eq!(
+            r#"[{"columns":["name"],"types":["text"],"values":[["fiona"]]},{"columns":[],"types":[],"values":[],"error":"missing parameter: :age"}]"#,
+            serde_json::to_string(&db.query(&req).unwrap()).unwrap()
+        );
+    }
 }
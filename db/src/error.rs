@@ -0,0 +1,133 @@
+use rusqlite::ErrorCode;
+use std::fmt;
+
+// SqliteError is an error reported by SQLite itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqliteError {
+    // the extended result code, e.g. 2067 (SQLITE_CONSTRAINT_UNIQUE)
+    pub code: i32,
+    pub message: String,
+}
+
+impl fmt::Display for SqliteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+// Error of the database. The SQLite errors are grouped by primary result code, and keep the extended code,
+// so callers can tell e.g. a constraint violation from a busy database.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    // a UNIQUE, NOT NULL, CHECK, PRIMARY KEY or FOREIGN KEY constraint failed
+    #[error("{0}")]
+    Constraint(SqliteError),
+    // the statement is invalid: a syntax error, an unknown table or column...
+    #[error("{0}")]
+    Syntax(SqliteError),
+    // the database is busy or locked by another connection, the request can be retried
+    #[error("{0}")]
+    Busy(SqliteError),
+    // the database can't be written
+    #[error("{0}")]
+    ReadOnly(SqliteError),
+    // the database file can't be opened
+    #[error("{0}")]
+    CantOpen(SqliteError),
+    // a disk I/O error, or the disk is full
+    #[error("{0}")]
+    Io(SqliteError),
+    // the database file is malformed
+    #[error("{0}")]
+    Corrupt(SqliteError),
+    // any other SQLite error
+    #[error("{0}")]
+    Sqlite(SqliteError),
+    // the parameters don't match the placeholders of the statement
+    #[error("{0}")]
+    Parameter(String),
+    // the snapshot isn't a valid database
+    #[error("invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+    // the connection to the database is closed
+    #[error("db connection is already closed")]
+    Closed,
+    // a temporary file of a snapshot can't be written or read
+    #[error(transparent)]
+    File(#[from] std::io::Error),
+    // any other error of rusqlite, e.g. a value which can't be converted
+    #[error("{0}")]
+    Other(String),
+}
+
+impl Error {
+    // returns the extended result code of an error reported by SQLite
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            Error::Constraint(err) | Error::Syntax(err) | Error::Busy(err) | Error::ReadOnly(err)
+            | Error::CantOpen(err) | Error::Io(err) | Error::Corrupt(err) | Error::Sqlite(err) => Some(err.code),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        match err {
+            rusqlite::Error::SqliteFailure(failure, message) => {
+                let err = SqliteError {
+                    code: failure.extended_code,
+                    message: message.unwrap_or_else(|| failure.to_string()),
+                };
+                match failure.code {
+                    ErrorCode::ConstraintViolation => Error::Constraint(err),
+                    // SQLITE_ERROR, the generic code of invalid statements
+                    ErrorCode::Unknown => Error::Syntax(err),
+                    ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => Error::Busy(err),
+                    ErrorCode::ReadOnly => Error::ReadOnly(err),
+                    ErrorCode::CannotOpen => Error::CantOpen(err),
+                    ErrorCode::SystemIoFailure | ErrorCode::DiskFull => Error::Io(err),
+                    ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase => Error::Corrupt(err),
+                    _ => Error::Sqlite(err),
+                }
+            }
+            rusqlite::Error::InvalidParameterName(_) | rusqlite::Error::InvalidParameterCount(_, _) => {
+                Error::Parameter(err.to_string())
+            }
+            _ => Error::Other(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_sqlite_errors() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert!(conn.execute("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, name TEXT NOT NULL)", []).is_ok());
+        assert!(conn.execute("INSERT INTO foo(id, name) VALUES(1, 'fiona')", []).is_ok());
+
+        // SQLITE_CONSTRAINT_PRIMARYKEY
+        let err = Error::from(conn.execute("INSERT INTO foo(id, name) VALUES(1, 'aoife')", []).unwrap_err());
+        assert!(matches!(err, Error::Constraint(_)));
+        assert_eq!(err.code(), Some(1555));
+        assert_eq!(err.to_string(), "UNIQUE constraint failed: foo.id");
+
+        // SQLITE_CONSTRAINT_NOTNULL
+        let err = Error::from(conn.execute("INSERT INTO foo(id) VALUES(2)", []).unwrap_err());
+        assert!(matches!(err, Error::Constraint(_)));
+        assert_eq!(err.code(), Some(1299));
+
+        let err = Error::from(conn.prepare("SELEC * FROM foo").unwrap_err());
+        assert!(matches!(err, Error::Syntax(_)));
+        assert_eq!(err.code(), Some(1));
+        assert_eq!(err.to_string(), r#"near "SELEC": syntax error"#);
+
+        let err = Error::from(conn.execute("INSERT INTO foo(id, name) VALUES(?, ?)", [1]).unwrap_err());
+        assert!(matches!(err, Error::Parameter(_)));
+        assert_eq!(err.code(), None);
+    }
+}
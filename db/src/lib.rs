@@ -1,2 +1,4 @@
 mod db;
-
+pub use crate::db::*;
+mod error;
+pub use crate::error::*;
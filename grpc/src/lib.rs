@@ -1 +1,6 @@
-mod raft_service;
\ No newline at end of file
+mod raft_service;
+pub use crate::raft_service::*;
+mod peer_sender;
+pub use crate::peer_sender::*;
+mod proposal_sender;
+pub use crate::proposal_sender::*;
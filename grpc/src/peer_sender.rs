@@ -0,0 +1,112 @@
+use std::sync::mpsc::SendError;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tonic::Request;
+use futures::FutureExt;
+use raft::prelude::Message;
+use protobuf::Message as PbMessage;
+use raftstore::PeerSender;
+use crate::raft_service::raft_api::raft_api_client::RaftApiClient;
+use crate::raft_service::raft_api::RaftMessage;
+
+// waiting time before connecting again to an unreachable node
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+// number of messages which can be buffered in an opening stream
+const STREAM_BUFFER_SIZE: usize = 1024;
+
+// GrpcPeerSender sends Raft messages to another node over the SendMessages stream.
+// Messages are queued and sent by a background task, so `send` never blocks the Raft loop.
+#[derive(Clone, Debug)]
+pub struct GrpcPeerSender {
+    sender_id: u64,
+    receiver_id: u64,
+    sender: UnboundedSender<Message>,
+}
+
+impl GrpcPeerSender {
+    // addr is the gRPC endpoint of the receiver, e.g.: http://127.0.0.1:5000
+    pub fn new(sender_id: u64, receiver_id: u64, addr: String, handle: &Handle) -> GrpcPeerSender {
+        let (tx, rx) = mpsc::unbounded_channel::<Message>();
+        handle.spawn(stream_messages(addr, rx));
+
+        GrpcPeerSender {
+            sender_id,
+            receiver_id,
+            sender: tx,
+        }
+    }
+}
+
+impl PeerSender for GrpcPeerSender {
+    type Message = Message;
+
+    fn send(&self, msg: Message) -> Result<(), SendError<Message>> {
+        self.sender.send(msg).map_err(|err| SendError(err.0))
+    }
+
+    fn sender_id(&self) -> u64 {
+        self.sender_id
+    }
+
+    fn receiver_id(&self) -> u64 {
+        self.receiver_id
+    }
+}
+
+// stream_messages keeps a SendMessages stream opened to addr and forwards every queued message into it.
+// It finishes when all GrpcPeerSender clones are dropped.
+// Messages queued while the node is unreachable are dropped: Raft will resend what is still needed.
+async fn stream_messages(addr: String, mut queue: UnboundedReceiver<Message>) {
+    loop {
+        let mut client = match RaftApiClient::connect(addr.clone()).await {
+            Ok(client) => client,
+            Err(err) => {
+                eprintln!("cannot connect to {}: {}", addr, err);
+                tokio::time::sleep(RECONNECT_INTERVAL).await;
+                while let Some(msg) = queue.recv().now_or_never() {
+                    if msg.is_none() {
+                        return;
+                    }
+                }
+                continue;
+            }
+        };
+
+        let (stream_tx, stream_rx) = mpsc::channel::<RaftMessage>(STREAM_BUFFER_SIZE);
+        let outbound = futures::stream::unfold(stream_rx, |mut rx| async move {
+            rx.recv().await.map(|msg| (msg, rx))
+        });
+        let mut call = tokio::spawn(async move {
+            client.send_messages(Request::new(outbound)).await
+        });
+
+        loop {
+            tokio::select! {
+                msg = queue.recv() => {
+                    let msg = match msg {
+                        Some(msg) => msg,
+                        // closing the stream lets the remote side finish the call
+                        None => return,
+                    };
+                    let data = match msg.write_to_bytes() {
+                        Ok(data) => data,
+                        Err(err) => {
+                            eprintln!("cannot encode raft message to {}: {}", addr, err);
+                            continue;
+                        }
+                    };
+                    if stream_tx.send(RaftMessage { data }).await.is_err() {
+                        break;
+                    }
+                }
+                res = &mut call => {
+                    eprintln!("stream to {} is closed: {:?}", addr, res);
+                    break;
+                }
+            }
+        }
+    }
+}
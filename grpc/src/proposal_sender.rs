@@ -0,0 +1,90 @@
+use std::sync::mpsc::SendError;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+use tonic::transport::Channel;
+use tonic::Request;
+use raftstore::{PeerSender, Proposal, ProposalError, ProposalResult};
+use crate::raft_service::raft_api::raft_api_client::RaftApiClient;
+use crate::raft_service::raft_api::ProposeRequest;
+
+// GrpcProposalSender forwards writes from a follower to the leader over the Propose call.
+// The response of the leader is relayed to the proposer, so clients can talk to any node.
+#[derive(Clone, Debug)]
+pub struct GrpcProposalSender {
+    sender_id: u64,
+    receiver_id: u64,
+    addr: String,
+    handle: Handle,
+    // connection shared by all forwarded proposals, opened on the first one
+    client: Arc<Mutex<Option<RaftApiClient<Channel>>>>,
+}
+
+impl GrpcProposalSender {
+    // addr is the gRPC endpoint of the receiver, e.g.: http://127.0.0.1:5000
+    pub fn new(sender_id: u64, receiver_id: u64, addr: String, handle: &Handle) -> GrpcProposalSender {
+        GrpcProposalSender {
+            sender_id,
+            receiver_id,
+            addr,
+            handle: handle.clone(),
+            client: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl PeerSender for GrpcProposalSender {
+    type Message = Proposal;
+
+    // only writes can be forwarded, other proposals are given back
+    fn send(&self, proposal: Proposal) -> Result<(), SendError<Proposal>> {
+        let request = match proposal.normal {
+            Some(ref request) => request.encode(),
+            None => return Err(SendError(proposal)),
+        };
+
+        let addr = self.addr.clone();
+        let client = self.client.clone();
+        self.handle.spawn(async move {
+            let timeout = proposal.deadline.saturating_duration_since(Instant::now());
+            let result = match tokio::time::timeout(timeout, propose(addr, client, request)).await {
+                Ok(result) => result,
+                Err(_) => Err(ProposalError::Timeout),
+            };
+            // the proposer may have given up waiting
+            let _ = proposal.propose_success.send(result);
+        });
+        Ok(())
+    }
+
+    fn sender_id(&self) -> u64 {
+        self.sender_id
+    }
+
+    fn receiver_id(&self) -> u64 {
+        self.receiver_id
+    }
+}
+
+// sends the encoded request to addr and waits until the leader applied it
+async fn propose(addr: String, client: Arc<Mutex<Option<RaftApiClient<Channel>>>>, request: Vec<u8>) -> ProposalResult {
+    let mut conn = {
+        let mut client = client.lock().await;
+        if client.is_none() {
+            let conn = RaftApiClient::connect(addr.clone()).await
+                .map_err(|err| ProposalError::Forward(format!("cannot connect to {}: {}", addr, err)))?;
+            *client = Some(conn);
+        }
+        client.as_ref().unwrap().clone()
+    };
+
+    match conn.propose(Request::new(ProposeRequest { request })).await {
+        Ok(response) => response.into_inner().into(),
+        Err(status) => {
+            // connect again on the next proposal
+            client.lock().await.take();
+            Err(ProposalError::Forward(status.to_string()))
+        }
+    }
+}
@@ -1,12 +1,16 @@
 use tokio::sync::oneshot::{Receiver, Sender};
-use tokio::runtime::{Builder, Runtime};
+use tokio::runtime::{Builder, Handle, Runtime};
 use tonic::transport::Server;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::sync::{mpsc, Mutex};
 use raft_api::raft_api_server::{RaftApi, RaftApiServer};
-use raft_api::raft_api_client::RaftApiClient;
-use raft_api::{HelloReply, HelloRequest};
-use tonic::{Request, Response, Status};
+use raft_api::{Done, RaftMessage, ProposeRequest, ProposeResponse, ExecuteResult, ProposeError};
+use raft_api::propose_error::Kind;
+use tonic::{Request, Response, Status, Streaming};
 use rand::Rng;
+use raft::prelude::Message;
+use protobuf::Message as PbMessage;
+use raftstore::{PeerMsg, Proposal, ProposalError, ProposalQueue, ProposalResult};
 
 // all grpc generated code will be generated inside this module
 pub mod raft_api {
@@ -14,32 +18,122 @@ pub mod raft_api {
 }
 
 // ServiceCore stores all data that need to access across requests
-#[derive(Default)]
-pub struct ServiceCore {}
+pub struct ServiceCore {
+    // messages received from other nodes are handed to the local Raft node (NetworkOutbound) through this channel
+    sender: Mutex<mpsc::Sender<PeerMsg>>,
+    // writes forwarded by followers are proposed to the local Raft node through this queue
+    proposal_queue: ProposalQueue,
+}
 
 #[tonic::async_trait]
 impl RaftApi for ServiceCore {
-    async fn say_hello(&self, request: Request<HelloRequest>) -> Result<Response<HelloReply>, Status> {
-        println!("Got a request: {:?}", request);
+    async fn send_messages(&self, request: Request<Streaming<RaftMessage>>) -> Result<Response<Done>, Status> {
+        let mut stream = request.into_inner();
+        while let Some(raft_message) = stream.message().await? {
+            let mut msg = Message::default();
+            if let Err(err) = msg.merge_from_bytes(&raft_message.data) {
+                return Err(Status::invalid_argument(err.to_string()));
+            }
+
+            if self.sender.lock().unwrap().send(PeerMsg::Raft(msg)).is_err() {
+                return Err(Status::unavailable("raft node is stopped"));
+            }
+        }
+
+        Ok(Response::new(Done {}))
+    }
+
+    async fn propose(&self, request: Request<ProposeRequest>) -> Result<Response<ProposeResponse>, Status> {
+        let req = command::Request::decode(&request.into_inner().request).map_err(Status::invalid_argument)?;
+        let (mut proposal, rx) = Proposal::normal(req);
+        let deadline = proposal.deadline;
+        // the proposal came from a follower: if this node isn't the leader anymore, it isn't forwarded again
+        proposal.forwarded = true;
+        self.proposal_queue.clone().add_proposal(proposal);
+
+        // the Raft loop completes the proposal on a std channel, wait for it outside of the runtime threads
+        let result = tokio::task::spawn_blocking(move || {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            rx.recv_timeout(timeout).unwrap_or(Err(ProposalError::Timeout))
+        }).await.map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(result.into()))
+    }
+}
+
+impl From<ProposalResult> for ProposeResponse {
+    fn from(result: ProposalResult) -> Self {
+        match result {
+            Ok(responses) => ProposeResponse {
+                results: responses.into_iter().map(|r| ExecuteResult {
+                    last_insert_id: r.last_insert_id,
+                    rows_affected: r.rows_affected,
+                    error: r.error,
+                }).collect(),
+                error: None,
+            },
+            Err(err) => ProposeResponse { results: vec![], error: Some(err.into()) },
+        }
+    }
+}
+
+impl From<ProposeResponse> for ProposalResult {
+    fn from(response: ProposeResponse) -> Self {
+        match response.error {
+            Some(err) => Err(err.into()),
+            None => Ok(response.results.into_iter().map(|r| command::Response {
+                last_insert_id: r.last_insert_id,
+                rows_affected: r.rows_affected,
+                error: r.error,
+            }).collect()),
+        }
+    }
+}
 
-        let reply = HelloReply {
-            message: format!("Hello {}!", request.into_inner().name).into(), // We must use .into_inner() as the fields of gRPC requests and responses are private
+impl From<ProposalError> for ProposeError {
+    fn from(err: ProposalError) -> Self {
+        let (kind, leader_id, message) = match err {
+            ProposalError::NotLeader { leader_id } => (Kind::NotLeader, leader_id, String::new()),
+            ProposalError::LeadershipLost => (Kind::LeadershipLost, 0, String::new()),
+            ProposalError::Timeout => (Kind::Timeout, 0, String::new()),
+            ProposalError::Dropped => (Kind::Dropped, 0, String::new()),
+            ProposalError::Stopped => (Kind::Stopped, 0, String::new()),
+            ProposalError::Forward(message) => (Kind::Forward, 0, message),
+            ProposalError::Apply(message) => (Kind::Apply, 0, message),
+            ProposalError::Invalid(message) => (Kind::Invalid, 0, message),
         };
+        ProposeError { kind: kind as i32, leader_id, message }
+    }
+}
 
-        Ok(Response::new(reply))
+impl From<ProposeError> for ProposalError {
+    fn from(err: ProposeError) -> Self {
+        match Kind::from_i32(err.kind) {
+            Some(Kind::NotLeader) => ProposalError::NotLeader { leader_id: err.leader_id },
+            Some(Kind::LeadershipLost) => ProposalError::LeadershipLost,
+            Some(Kind::Timeout) => ProposalError::Timeout,
+            Some(Kind::Dropped) => ProposalError::Dropped,
+            Some(Kind::Stopped) => ProposalError::Stopped,
+            Some(Kind::Forward) => ProposalError::Forward(err.message),
+            Some(Kind::Apply) => ProposalError::Apply(err.message),
+            Some(Kind::Invalid) => ProposalError::Invalid(err.message),
+            None => ProposalError::Forward(format!("unknown error kind: {}", err.kind)),
+        }
     }
 }
 
 // RaftService provides a gRPC service to communicate between Raft instances
-struct RaftService {
+pub struct RaftService {
     addr: String,
     thread_pool: Runtime,
     tx: Sender<()>,
     rx: Option<Receiver<()>>,
+    sender: mpsc::Sender<PeerMsg>,
+    proposal_queue: ProposalQueue,
 }
 
 impl RaftService {
-    pub fn new(thread_size: usize, addr: String) -> RaftService {
+    pub fn new(thread_size: usize, addr: String, sender: mpsc::Sender<PeerMsg>, proposal_queue: ProposalQueue) -> RaftService {
         // manually setup runtime environment instead of using the conventional macro #[tokio::main]
         let thread_pool = Builder::new_multi_thread()
             .enable_all()
@@ -61,6 +155,8 @@ impl RaftService {
             thread_pool,
             tx,
             rx: Some(rx),
+            sender,
+            proposal_queue,
         }
     }
 
@@ -68,7 +164,10 @@ impl RaftService {
         let addr = self.addr.parse().expect("Unable to parse socket address");
 
         let rx = self.rx.take().unwrap();
-        let core = ServiceCore::default();
+        let core = ServiceCore {
+            sender: Mutex::new(self.sender.clone()),
+            proposal_queue: self.proposal_queue.clone(),
+        };
         let server = Server::builder()
             .add_service(RaftApiServer::new(core))
             .serve_with_shutdown(addr, async move {
@@ -96,31 +195,115 @@ impl RaftService {
     pub fn listening_addr(&self) -> String {
         self.addr.clone()
     }
+
+    // Return the handle of the underlying runtime, so outgoing connections (GrpcPeerSender) can share its threads
+    pub fn handle(&self) -> Handle {
+        self.thread_pool.handle().clone()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use tokio_test::block_on;
+    use raft_api::raft_api_client::RaftApiClient;
+    use raftstore::PeerSender;
+    use crate::peer_sender::GrpcPeerSender;
+    use crate::proposal_sender::GrpcProposalSender;
+    use command::{Parameters, Statement};
+    use std::thread;
+
+    // blocks until the service accepts connections
+    fn wait_for_service(service: &RaftService) {
+        let addr = format!("http://{}", service.listening_addr());
+        let handle = service.thread_pool.spawn(async move {
+            while RaftApiClient::connect(addr.clone()).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        block_on(handle).unwrap();
+    }
 
     #[test]
-    fn test_dump() {
-        let mut service = RaftService::new(1, "".to_string());
+    fn test_send_messages() {
+        let (tx, rx) = mpsc::channel();
+        let mut service = RaftService::new(1, "".to_string(), tx, ProposalQueue::new());
         service.start_test_server();
+        wait_for_service(&service);
 
         let addr = format!("http://{}", service.listening_addr());
-        let handle = service.thread_pool.spawn(async move {
-            let mut client = RaftApiClient::connect(addr).await.unwrap();
-            let request = tonic::Request::new(HelloRequest {
-                name: "Tonic".into(),
-            });
+        let sender = GrpcPeerSender::new(1, 2, addr, &service.handle());
+        assert_eq!(sender.sender_id(), 1);
+        assert_eq!(sender.receiver_id(), 2);
+
+        for i in 1..4 {
+            let mut msg = Message::default();
+            msg.from = 1;
+            msg.to = 2;
+            msg.index = i;
+            sender.send(msg).unwrap();
+        }
+
+        for i in 1..4 {
+            let msg = match rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+                PeerMsg::Raft(msg) => msg,
+                msg => panic!("unexpected message {:?}", msg),
+            };
+            assert_eq!(msg.from, 1);
+            assert_eq!(msg.to, 2);
+            assert_eq!(msg.index, i);
+        }
 
-            let response = client.say_hello(request).await.unwrap();
-            assert_eq!(response.into_inner().message, "Hello Tonic!");
+        drop(sender);
+        service.stop();
+    }
+
+    #[test]
+    fn test_propose() {
+        let (tx, _rx) = mpsc::channel();
+        let mut queue = ProposalQueue::new();
+        let mut service = RaftService::new(1, "".to_string(), tx, queue.clone());
+        service.start_test_server();
+        wait_for_service(&service);
+
+        // plays the leader: the first write is applied, the second one fails
+        let leader = thread::spawn(move || {
+            let mut results = vec![
+                Ok(vec![command::Response { last_insert_id: 7, rows_affected: 1, error: String::new() }]),
+                Err(ProposalError::NotLeader { leader_id: 3 }),
+            ].into_iter();
+            while let Some(result) = results.next() {
+                let proposal = loop {
+                    match queue.remove_proposal() {
+                        Some(proposal) => break proposal,
+                        None => thread::sleep(Duration::from_millis(10)),
+                    }
+                };
+                assert!(proposal.forwarded);
+                assert_eq!(proposal.normal.as_ref().unwrap().statements[0].sql, "DELETE FROM foo");
+                proposal.propose_success.send(result).unwrap();
+            }
         });
 
-        block_on(handle).unwrap();
+        let addr = format!("http://{}", service.listening_addr());
+        let sender = GrpcProposalSender::new(2, 1, addr, &service.handle());
+        let request = command::Request {
+            transaction: false,
+            statements: Box::new([Statement { sql: "DELETE FROM foo".to_string(), parameters: Parameters::default() }]),
+        };
 
+        let (proposal, rx) = Proposal::normal(request.clone());
+        sender.send(proposal).unwrap();
+        let responses = rx.recv_timeout(Duration::from_secs(10)).unwrap().unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].last_insert_id, 7);
+
+        let (proposal, rx) = Proposal::normal(request);
+        sender.send(proposal).unwrap();
+        let err = rx.recv_timeout(Duration::from_secs(10)).unwrap().unwrap_err();
+        assert_eq!(err, ProposalError::NotLeader { leader_id: 3 });
+
+        leader.join().unwrap();
         service.stop();
     }
-}
\ No newline at end of file
+}
@@ -8,13 +8,28 @@ use futures::{TryFutureExt, TryStreamExt};
 use tokio::runtime::{Builder, Runtime};
 use tokio::sync::oneshot::{Sender, Receiver};
 use std::time::Duration;
-use store::{Database, RaftControl};
-use serde::Serialize;
+use store::{Database, DbError, Error, RaftControl};
+use serde::{Deserialize, Serialize};
 use futures::future::ok;
-use command::ExecuteRequest;
+use command::{BlobEncoding, ConsistencyLevel, ExecuteRequest, Parameters, QueryRequest, Statement};
+use hyper::Uri;
 
 pub trait DbStore: Database + RaftControl + Clone + Send + 'static {}
 
+// ErrorResponse is the body of a failed request. A node which isn't the leader names the leader it knows, if any
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader_id: Option<u64>,
+}
+
+// TransferLeaderRequest is the body of a leader transfer: the ID of the next leader
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TransferLeaderRequest {
+    pub id: String,
+}
+
 // Service provides a HTTP service
 pub struct Service<T> where T: DbStore {
     addr: String,
@@ -112,20 +127,16 @@ async fn router<T>(srv: ServiceCore<T>, req: Request<Body>) -> Result<Response<B
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/ping") => Ok(Response::new(Body::from("pong"))),
         (&Method::POST, "/db/execute") => { execute_query(srv.clone(), req).await }
+        (&Method::GET, "/db/query") | (&Method::POST, "/db/query") => { query(srv.clone(), req).await }
+        (&Method::POST, "/leader/transfer") => { transfer_leader(srv.clone(), req).await }
 
         // Return the 404 Not Found for other routes.
-        _ => err_response(StatusCode::NOT_FOUND, "")
+        _ => err_response(StatusCode::NOT_FOUND, "not found")
     }
 }
 
 async fn execute_query<T>(core: ServiceCore<T>, req: Request<Body>) -> hyper::Result<Response<Body>> where T: DbStore {
-    let mut body = Vec::new();
-    req.into_body()
-        .try_for_each(|bytes| {
-            body.extend(bytes);
-            ok(())
-        })
-        .await?;
+    let body = read_body(req).await?;
 
     let r: ExecuteRequest = match serde_json::from_slice(&body) {
         Ok(er) => er,
@@ -140,20 +151,149 @@ async fn execute_query<T>(core: ServiceCore<T>, req: Request<Body>) -> hyper::Re
     let store = &mut core.store.lock().unwrap();
     return match store.execute(r) {
         Ok(result) => success_response(result),
-        Err(err) => err_response(
-            StatusCode::BAD_REQUEST,
-            err.to_string(),
-        )
+        Err(err) => store_err_response(err),
+    };
+}
+
+// query serves read-only statements: either from the `q` parameters of a GET request
+// or from a json encoded QueryRequest in the body of a POST request.
+// The `level` parameter of the url overrides the consistency level of the request,
+// the `blob` parameter is the encoding of the BLOB values in the response
+async fn query<T>(core: ServiceCore<T>, req: Request<Body>) -> hyper::Result<Response<Body>> where T: DbStore {
+    let level = level_from_uri(req.uri());
+    let encoding = match blob_encoding_from_uri(req.uri()) {
+        Ok(encoding) => encoding,
+        Err(err) => return err_response(StatusCode::BAD_REQUEST, err),
+    };
+    let parsed = match req.method() {
+        &Method::GET => query_request_from_uri(req.uri()),
+        _ => {
+            let body = read_body(req).await?;
+            serde_json::from_slice::<QueryRequest>(&body).map_err(|err| err.to_string())
+        }
+    };
+
+    let r = match parsed.and_then(|qr| level.map(|level| QueryRequest { level: level.unwrap_or(qr.level), ..qr })) {
+        Ok(qr) => qr,
+        Err(err) => {
+            return err_response(
+                StatusCode::BAD_REQUEST,
+                err,
+            );
+        }
+    };
+
+    let store = core.store.lock().unwrap();
+    return match store.query(r) {
+        Ok(mut result) => {
+            result.iter_mut().for_each(|rows| rows.encode_blobs(encoding));
+            success_response(result)
+        }
+        Err(err) => store_err_response(err),
     };
 }
 
-// err_response serialize error request with message and error code
+// transfer_leader hands the leadership over to another node, so this one can be drained
+async fn transfer_leader<T>(core: ServiceCore<T>, req: Request<Body>) -> hyper::Result<Response<Body>> where T: DbStore {
+    let body = read_body(req).await?;
+
+    let r: TransferLeaderRequest = match serde_json::from_slice(&body) {
+        Ok(r) => r,
+        Err(err) => {
+            return err_response(
+                StatusCode::BAD_REQUEST,
+                err.to_string(),
+            );
+        }
+    };
+
+    let store = &mut core.store.lock().unwrap();
+    return match store.transfer_leader(r.id) {
+        Ok(_) => Ok(Response::new(Body::empty())),
+        Err(err) => store_err_response(err),
+    };
+}
+
+// builds a QueryRequest from the url: each `q` parameter is a single statement without parameters
+fn query_request_from_uri(uri: &Uri) -> Result<QueryRequest, String> {
+    let statements: Vec<Statement> = form_urlencoded::parse(uri.query().unwrap_or("").as_bytes())
+        .filter(|(key, _)| key == "q")
+        .map(|(_, sql)| Statement { sql: sql.into_owned(), parameters: Parameters::default() })
+        .collect();
+
+    if statements.is_empty() {
+        return Err(String::from("missing query parameter: q"));
+    }
+
+    Ok(QueryRequest {
+        request: command::Request {
+            transaction: false,
+            statements: statements.into_boxed_slice(),
+        },
+        level: ConsistencyLevel::default(),
+    })
+}
+
+// the consistency level given by the `level` parameter of the url, if any
+fn level_from_uri(uri: &Uri) -> Result<Option<ConsistencyLevel>, String> {
+    form_urlencoded::parse(uri.query().unwrap_or("").as_bytes())
+        .find(|(key, _)| key == "level")
+        .map(|(_, level)| level.parse())
+        .transpose()
+}
+
+// the encoding given by the `blob` parameter of the url, base64 by default
+fn blob_encoding_from_uri(uri: &Uri) -> Result<BlobEncoding, String> {
+    form_urlencoded::parse(uri.query().unwrap_or("").as_bytes())
+        .find(|(key, _)| key == "blob")
+        .map_or(Ok(BlobEncoding::default()), |(_, encoding)| encoding.parse())
+}
+
+// read_body collects the whole request body
+async fn read_body(req: Request<Body>) -> hyper::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    req.into_body()
+        .try_for_each(|bytes| {
+            body.extend(bytes);
+            ok(())
+        })
+        .await?;
+    Ok(body)
+}
+
+// err_response serializes the error message to a json ErrorResponse, with the status code
 fn err_response<M>(status_code: StatusCode, message: M) -> hyper::Result<Response<Body>>
-    where M: Into<Body>
+    where M: Into<String>
 {
+    json_err_response(status_code, ErrorResponse { error: message.into(), leader_id: None })
+}
+
+// store_err_response maps the errors of the store to status codes: the request is wrong (400), names an
+// unknown node (404), conflicts with the data (409), may be retried later or on another node (503),
+// or didn't complete in time (504). Other failures of the node are internal errors (500)
+fn store_err_response(err: Error) -> hyper::Result<Response<Body>> {
+    let status_code = match &err {
+        Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        Error::NodeNotFound(_) => StatusCode::NOT_FOUND,
+        Error::NotLeader { .. } | Error::ShuttingDown | Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        Error::Db(DbError::Syntax(_)) | Error::Db(DbError::Parameter(_)) => StatusCode::BAD_REQUEST,
+        Error::Db(DbError::Constraint(_)) => StatusCode::CONFLICT,
+        Error::Db(DbError::Busy(_)) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::Db(_) | Error::Apply(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let leader_id = match err {
+        Error::NotLeader { leader_id } if leader_id != 0 => Some(leader_id),
+        _ => None,
+    };
+    json_err_response(status_code, ErrorResponse { error: err.to_string(), leader_id })
+}
+
+fn json_err_response(status_code: StatusCode, body: ErrorResponse) -> hyper::Result<Response<Body>> {
     Ok(Response::builder()
         .status(status_code)
-        .body(message.into())
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&body).unwrap()))
         .unwrap()
     )
 }
@@ -174,10 +314,9 @@ fn success_response<M>(message: M) -> hyper::Result<Response<Body>>
 #[cfg(test)]
 mod tests {
     use super::*;
-    use hyper::Uri;
     use tokio_test::block_on;
-    use command::{ExecuteRequest, QueryRequest, Rows, Statement};
-    use store::Error;
+    use command::{ExecuteRequest, QueryRequest, Rows, Statement, Value, DataType};
+    use store::SqliteError;
 
     #[derive(Default, Clone)]
     struct MockStore {}
@@ -194,10 +333,31 @@ mod tests {
         fn leader_id(&self) -> Result<String, Error> {
             Ok("1".to_string())
         }
+
+        fn change_membership(&mut self, _voters: Vec<String>, _learners: Vec<String>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn transfer_leader(&mut self, id: String) -> Result<(), Error> {
+            if id == "2" {
+                return Ok(());
+            }
+            Err(Error::NodeNotFound(id))
+        }
     }
 
     impl Database for MockStore {
-        fn execute(&mut self, _req: ExecuteRequest) -> Result<Vec<command::Response>, Error> {
+        // the statements "conflict", "timeout" and "stop" fail the request
+        fn execute(&mut self, req: ExecuteRequest) -> Result<Vec<command::Response>, Error> {
+            match req.request.statements.first().map(|stmt| stmt.sql.as_str()) {
+                Some("conflict") => return Err(Error::Db(DbError::Constraint(SqliteError {
+                    code: 1555,
+                    message: "UNIQUE constraint failed: foo.id".to_string(),
+                }))),
+                Some("timeout") => return Err(Error::Timeout),
+                Some("stop") => return Err(Error::ShuttingDown),
+                _ => {}
+            }
             let mut results = Vec::new();
             results.push(command::Response {
                 last_insert_id: 1,
@@ -212,8 +372,38 @@ mod tests {
             Ok(results)
         }
 
-        fn query(&self, _req: QueryRequest) -> Result<Rows<'static>, Error> {
-            todo!()
+        // echoes every statement back as a single row, or an error for an empty statement.
+        // The blobs table holds a single blob.
+        // It acts as a follower of node 2, which cannot serve strong reads
+        fn query(&self, req: QueryRequest) -> Result<Vec<Rows>, Error> {
+            if req.level == ConsistencyLevel::Strong {
+                return Err(Error::NotLeader { leader_id: 2 });
+            }
+            let results = req.request.statements.iter().map(|stmt| {
+                if stmt.sql.is_empty() {
+                    return Rows {
+                        columns: vec![],
+                        types: vec![],
+                        values: vec![],
+                        error: "empty statement".to_string(),
+                    };
+                }
+                if stmt.sql == "SELECT data FROM blobs" {
+                    return Rows {
+                        columns: vec!["data".to_string()],
+                        types: vec![DataType::Blob],
+                        values: vec![vec![Value::Blob(vec![0, 255, 16])]],
+                        error: "".to_string(),
+                    };
+                }
+                Rows {
+                    columns: vec!["sql".to_string()],
+                    types: vec![DataType::Text],
+                    values: vec![vec![Value::Text(stmt.sql.clone())]],
+                    error: "".to_string(),
+                }
+            }).collect();
+            Ok(results)
         }
     }
 
@@ -286,7 +476,7 @@ mod tests {
                     statements: Box::new([
                         Statement {
                             sql: r#"INSERT INTO foo(id, name) VALUES(1, "fiona")"#.to_string(),
-                            parameters: Box::new([]),
+                            parameters: Parameters::default(),
                         },
                     ]),
                 }
@@ -314,4 +504,253 @@ mod tests {
         block_on(handle).unwrap();
         service.stop();
     }
+
+    #[test]
+    fn test_execute_errors() {
+        let mut service = Service::new(1, "127.0.0.1:0".to_string(), MockStore {});
+        service.start();
+
+        let endpoint = Uri::builder()
+            .scheme("http")
+            .authority(service.listening_addr().to_string().as_str())
+            .path_and_query("/db/execute")
+            .build()
+            .unwrap();
+
+        let handle = service.thread_pool.spawn(async move {
+            let cases = vec![
+                ("conflict", StatusCode::CONFLICT, r#"{"error":"database error: UNIQUE constraint failed: foo.id"}"#),
+                ("timeout", StatusCode::GATEWAY_TIMEOUT, r#"{"error":"request timed out"}"#),
+                ("stop", StatusCode::SERVICE_UNAVAILABLE, r#"{"error":"node is shutting down"}"#),
+            ];
+            for (sql, status, text) in cases {
+                let body = serde_json::to_string(&command::ExecuteRequest {
+                    request: command::Request {
+                        transaction: false,
+                        statements: Box::new([Statement { sql: sql.to_string(), parameters: Parameters::default() }]),
+                    }
+                }).unwrap();
+                let mut req = Request::new(Body::from(body));
+                *req.method_mut() = Method::POST;
+                *req.uri_mut() = endpoint.clone();
+
+                let resp = Client::new().request(req).await.unwrap();
+                assert_eq!(resp.status(), status);
+                assert_eq!(resp.headers()[hyper::header::CONTENT_TYPE], "application/json");
+                let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                assert_eq!(String::from_utf8(bytes.into_iter().collect()).unwrap(), text);
+            }
+
+            // a malformed body
+            let mut req = Request::new(Body::from("{"));
+            *req.method_mut() = Method::POST;
+            *req.uri_mut() = endpoint.clone();
+            let resp = Client::new().request(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let err: ErrorResponse = serde_json::from_slice(&bytes).unwrap();
+            assert!(err.error.starts_with("EOF while parsing"));
+        });
+
+        block_on(handle).unwrap();
+        service.stop();
+    }
+
+    #[test]
+    fn test_query_get() {
+        let mut service = Service::new(1, "127.0.0.1:0".to_string(), MockStore {});
+        service.start();
+
+        let endpoint = Uri::builder()
+            .scheme("http")
+            .authority(service.listening_addr().to_string().as_str())
+            .path_and_query("/db/query?q=SELECT%20*%20FROM%20foo&q=SELECT+1")
+            .build()
+            .unwrap();
+
+        let client = Client::new();
+        let handle = service.thread_pool.spawn(async move {
+            let resp = client.get(endpoint).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let text = String::from_utf8(bytes.into_iter().collect()).unwrap();
+            assert_eq!(
+                r#"[{"columns":["sql"],"types":["text"],"values":[["SELECT * FROM foo"]]},{"columns":["sql"],"types":["text"],"values":[["SELECT 1"]]}]"#,
+                text
+            );
+        });
+
+        block_on(handle).unwrap();
+        service.stop();
+    }
+
+    #[test]
+    fn test_query_get_missing_statement() {
+        let mut service = Service::new(1, "127.0.0.1:0".to_string(), MockStore {});
+        service.start();
+
+        let endpoint = Uri::builder()
+            .scheme("http")
+            .authority(service.listening_addr().to_string().as_str())
+            .path_and_query("/db/query")
+            .build()
+            .unwrap();
+
+        let client = Client::new();
+        let handle = service.thread_pool.spawn(async move {
+            let resp = client.get(endpoint).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        });
+
+        block_on(handle).unwrap();
+        service.stop();
+    }
+
+    #[test]
+    fn test_query_level() {
+        let mut service = Service::new(1, "127.0.0.1:0".to_string(), MockStore {});
+        service.start();
+
+        let addr = service.listening_addr().to_string();
+        let handle = service.thread_pool.spawn(async move {
+            let cases = vec![
+                ("none", StatusCode::OK, r#"[{"columns":["sql"],"types":["text"],"values":[["SELECT 1"]]}]"#),
+                ("linearizable", StatusCode::OK, r#"[{"columns":["sql"],"types":["text"],"values":[["SELECT 1"]]}]"#),
+                ("strong", StatusCode::SERVICE_UNAVAILABLE, r#"{"error":"node is not the leader, leader is 2","leader_id":2}"#),
+                ("eventual", StatusCode::BAD_REQUEST, r#"{"error":"unknown consistency level: eventual"}"#),
+            ];
+            for (level, status, text) in cases {
+                let endpoint = Uri::builder()
+                    .scheme("http")
+                    .authority(addr.as_str())
+                    .path_and_query(format!("/db/query?q=SELECT+1&level={}", level).as_str())
+                    .build()
+                    .unwrap();
+
+                let resp = Client::new().get(endpoint).await.unwrap();
+                assert_eq!(resp.status(), status);
+                let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                assert_eq!(String::from_utf8(bytes.into_iter().collect()).unwrap(), text);
+            }
+        });
+
+        block_on(handle).unwrap();
+        service.stop();
+    }
+
+    #[test]
+    fn test_query_blob() {
+        let mut service = Service::new(1, "127.0.0.1:0".to_string(), MockStore {});
+        service.start();
+
+        let addr = service.listening_addr().to_string();
+        let handle = service.thread_pool.spawn(async move {
+            let cases = vec![
+                ("", StatusCode::OK, r#"[{"columns":["data"],"types":["blob"],"values":[["AP8Q"]]}]"#),
+                ("&blob=base64", StatusCode::OK, r#"[{"columns":["data"],"types":["blob"],"values":[["AP8Q"]]}]"#),
+                ("&blob=hex", StatusCode::OK, r#"[{"columns":["data"],"types":["blob"],"values":[["00ff10"]]}]"#),
+                ("&blob=binary", StatusCode::BAD_REQUEST, r#"{"error":"unknown blob encoding: binary"}"#),
+            ];
+            for (encoding, status, text) in cases {
+                let endpoint = Uri::builder()
+                    .scheme("http")
+                    .authority(addr.as_str())
+                    .path_and_query(format!("/db/query?q=SELECT+data+FROM+blobs{}", encoding).as_str())
+                    .build()
+                    .unwrap();
+
+                let resp = Client::new().get(endpoint).await.unwrap();
+                assert_eq!(resp.status(), status);
+                let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                assert_eq!(String::from_utf8(bytes.into_iter().collect()).unwrap(), text);
+            }
+        });
+
+        block_on(handle).unwrap();
+        service.stop();
+    }
+
+    #[test]
+    fn test_query_post() {
+        let mut service = Service::new(1, "127.0.0.1:0".to_string(), MockStore {});
+        service.start();
+
+        let endpoint = Uri::builder()
+            .scheme("http")
+            .authority(service.listening_addr().to_string().as_str())
+            .path_and_query("/db/query")
+            .build()
+            .unwrap();
+
+        let mut req = Request::new(Body::from(
+            serde_json::to_string(&command::QueryRequest {
+                request: command::Request {
+                    transaction: false,
+                    statements: Box::new([
+                        Statement {
+                            sql: "SELECT * FROM foo WHERE id=?".to_string(),
+                            parameters: Parameters::Positional(Box::new([command::Parameter::Integer(1)])),
+                        },
+                        Statement {
+                            sql: "".to_string(),
+                            parameters: Parameters::default(),
+                        },
+                    ]),
+                },
+                level: ConsistencyLevel::None,
+            }).unwrap(),
+        ));
+        *req.method_mut() = Method::POST;
+        *req.uri_mut() = endpoint;
+        req.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("application/json"),
+        );
+
+        let handle = service.thread_pool.spawn(async move {
+            let resp = Client::new().request(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let text = String::from_utf8(bytes.into_iter().collect()).unwrap();
+            assert_eq!(
+                r#"[{"columns":["sql"],"types":["text"],"values":[["SELECT * FROM foo WHERE id=?"]]},{"columns":[],"types":[],"values":[],"error":"empty statement"}]"#,
+                text
+            );
+        });
+
+        block_on(handle).unwrap();
+        service.stop();
+    }
+
+    #[test]
+    fn test_transfer_leader() {
+        let mut service = Service::new(1, "127.0.0.1:0".to_string(), MockStore {});
+        service.start();
+
+        let endpoint = Uri::builder()
+            .scheme("http")
+            .authority(service.listening_addr().to_string().as_str())
+            .path_and_query("/leader/transfer")
+            .build()
+            .unwrap();
+
+        let handle = service.thread_pool.spawn(async move {
+            for (id, status, text) in vec![("2", StatusCode::OK, ""), ("3", StatusCode::NOT_FOUND, r#"{"error":"unknown node: 3"}"#)] {
+                let body = serde_json::to_string(&TransferLeaderRequest { id: id.to_string() }).unwrap();
+                let mut req = Request::new(Body::from(body));
+                *req.method_mut() = Method::POST;
+                *req.uri_mut() = endpoint.clone();
+
+                let resp = Client::new().request(req).await.unwrap();
+                assert_eq!(resp.status(), status);
+                let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                assert_eq!(String::from_utf8(bytes.into_iter().collect()).unwrap(), text);
+            }
+        });
+
+        block_on(handle).unwrap();
+        service.stop();
+    }
 }
@@ -0,0 +1,100 @@
+// measures the time from proposing a single write until it's applied on the leader, one write at a time.
+// The baseline run doesn't wake the leader's poller on a new proposal: like the former 100ms recv_timeout loop,
+// the proposal waits for the next tick or Raft message.
+// run with: cargo bench -p raftstore --bench commit_latency
+use raftstore::*;
+use raft::{prelude::*};
+use command::{Parameters, Request, Response, Statement};
+use std::time::{Duration, Instant};
+
+struct NoopStateMachine;
+
+impl StateMachine for NoopStateMachine {
+    fn apply(&mut self, req: &Request) -> Result<Vec<Response>, String> {
+        Ok(req.statements.iter().map(|_| Response { last_insert_id: 0, rows_affected: 1, error: String::new() }).collect())
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>, String> {
+        Ok(vec![])
+    }
+
+    fn restore(&mut self, _data: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn propose(network: &mut VirtualNetwork, node: u64, request: Request) -> ProposalResult {
+    let (proposal, rx) = Proposal::normal(request);
+    network.proposal_queues.get_mut(&node).unwrap().add_proposal(proposal);
+    rx.recv().unwrap()
+}
+
+// returns the sorted latencies of the writes on a new cluster of n nodes
+fn run(n: u64, writes: u32, wake: bool) -> Vec<Duration> {
+    let leader_id = 1;
+
+    let mut network = VirtualNetwork::new(n);
+    let mut pollers = Vec::new();
+    for node in 1..n + 1 {
+        let (_, poller) = start_for_testing(
+            node,
+            NoopStateMachine,
+            network.network_inbounds.remove(&node).unwrap(),
+            network.network_outbounds.remove(&node).unwrap(),
+            network.proposal_queues.get(&node).unwrap().clone(),
+            &RaftConfig::default(),
+            node == leader_id);
+        pollers.push(poller);
+    }
+    network.async_start();
+
+    for node in 2..n + 1 {
+        let mut conf_change = ConfChange::default();
+        conf_change.node_id = node;
+        conf_change.set_change_type(ConfChangeType::AddNode);
+        let (proposal, rx) = Proposal::conf_change(&conf_change);
+        network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
+        rx.recv().unwrap().unwrap();
+    }
+    if !wake {
+        network.proposal_queues.get_mut(&leader_id).unwrap().remove_waker();
+    }
+
+    let request = Request {
+        transaction: false,
+        statements: Box::new([Statement { sql: String::from("INSERT INTO foo(name) VALUES('fiona')"), parameters: Parameters::default() }]),
+    };
+    let mut latencies: Vec<Duration> = (0..writes).map(|_| {
+        let start = Instant::now();
+        propose(&mut network, leader_id, request.clone()).unwrap();
+        start.elapsed()
+    }).collect();
+    latencies.sort();
+
+    network.stop();
+    for poller in pollers {
+        poller.stop();
+    }
+    latencies
+}
+
+fn report(name: &str, latencies: &[Duration]) -> Duration {
+    let total: Duration = latencies.iter().sum();
+    let mean = total / latencies.len() as u32;
+    println!("{}:", name);
+    println!("  mean {:?}", mean);
+    println!("  p50  {:?}", latencies[latencies.len() / 2]);
+    println!("  p99  {:?}", latencies[latencies.len() * 99 / 100]);
+    println!("  max  {:?}", latencies[latencies.len() - 1]);
+    mean
+}
+
+fn main() {
+    let n = 3;
+    let writes = 200;
+
+    println!("single write commit latency over {} writes on {} nodes", writes, n);
+    let baseline = report("baseline, proposals wait for the next tick", &run(n, writes, false));
+    let woken = report("proposals wake the poller", &run(n, writes, true));
+    println!("mean speedup: {:.1}x", baseline.as_secs_f64() / woken.as_secs_f64());
+}
@@ -0,0 +1,272 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use raft::prelude::*;
+use command::RequestBatch;
+use crate::compaction::CompactionConfig;
+use crate::msg::{PeerMsg, Proposal, ProposalError};
+use crate::state_machine::StateMachine;
+
+// ApplyTask is sent by the Raft thread to the apply worker, tasks are handled in order
+pub enum ApplyTask {
+    // committed entries, each one with the proposals waiting for its responses.
+    // The configuration is the one of the cluster once the entries are applied
+    Apply { entries: Vec<(Entry, Vec<Proposal>)>, conf_state: ConfState },
+    // replaces the state by a snapshot received from the leader
+    Restore(Snapshot),
+}
+
+// ApplyResult tells the Raft thread how far the state machine is
+#[derive(Debug)]
+pub struct ApplyResult {
+    // index of the last entry applied to the state machine
+    pub applied_index: u64,
+    // state of the state machine at the applied index, taken once the compaction thresholds are reached
+    pub snapshot: Option<(ConfState, Vec<u8>)>,
+}
+
+// ApplyWorker applies the committed entries to the state machine on its own thread,
+// so a slow state machine doesn't keep the Raft thread from ticking and replicating.
+// It stops once the Raft thread drops it
+pub struct ApplyWorker {
+    sender: Option<Sender<ApplyTask>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ApplyWorker {
+    // starts the worker on a state machine which applied the entries up to applied_index
+    pub fn start<M>(
+        id: u64,
+        state_machine: M,
+        applied_index: u64,
+        compaction: CompactionConfig,
+        mailbox: Sender<PeerMsg>,
+    ) -> Self
+        where M: StateMachine + Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel();
+        let mut apply = Apply {
+            id,
+            state_machine,
+            compaction,
+            applied_index,
+            saved_index: applied_index,
+            applied_entries: 0,
+            applied_bytes: 0,
+        };
+        let handle = thread::spawn(move || apply.run(receiver, mailbox));
+        ApplyWorker { sender: Some(sender), handle: Some(handle) }
+    }
+
+    pub fn schedule(&self, task: ApplyTask) {
+        if self.sender.as_ref().unwrap().send(task).is_err() {
+            eprintln!("apply worker is stopped");
+        }
+    }
+}
+
+impl Drop for ApplyWorker {
+    // waits for the tasks already scheduled: once the worker is dropped, the state machine can be used by a new node
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct Apply<M: StateMachine> {
+    id: u64,
+    state_machine: M,
+    compaction: CompactionConfig,
+    applied_index: u64,
+    // applied index recorded by the state machine
+    saved_index: u64,
+    // entries and bytes applied since the latest snapshot
+    applied_entries: u64,
+    applied_bytes: u64,
+}
+
+impl<M: StateMachine> Apply<M> {
+    fn run(&mut self, receiver: Receiver<ApplyTask>, mailbox: Sender<PeerMsg>) {
+        for task in receiver {
+            let result = match task {
+                ApplyTask::Apply { entries, conf_state } => self.apply(entries, conf_state),
+                ApplyTask::Restore(snapshot) => match self.restore(snapshot) {
+                    Ok(result) => result,
+                    Err(err) => {
+                        // the state machine doesn't match the log anymore, nothing else can be applied to it
+                        let _ = mailbox.send(PeerMsg::ApplyFailed(err));
+                        return;
+                    }
+                },
+            };
+            // the Raft thread may be stopping, the remaining tasks are applied anyway
+            let _ = mailbox.send(PeerMsg::Applied(result));
+        }
+    }
+
+    fn apply(&mut self, entries: Vec<(Entry, Vec<Proposal>)>, conf_state: ConfState) -> ApplyResult {
+        for (entry, proposals) in entries {
+            self.applied_index = entry.index;
+            self.applied_entries += 1;
+            self.applied_bytes += entry.data.len() as u64;
+            // conf changes and empty entries are handled by the Raft thread
+            if entry.data.is_empty() || entry.get_entry_type() != EntryType::EntryNormal {
+                continue;
+            }
+
+            // decode the batch and apply its requests in order to the state machine, which records the
+            // applied index with them. Each proposal of the batch gets the responses of its own request.
+            let applied = RequestBatch::decode(&entry.data)
+                .and_then(|requests| self.state_machine.apply_entry(entry.index, &requests));
+            let results: Vec<_> = match applied {
+                Ok(results) => {
+                    self.saved_index = entry.index;
+                    results.into_iter().map(|r| r.map_err(ProposalError::Apply)).collect()
+                }
+                Err(err) => proposals.iter().map(|_| Err(ProposalError::Apply(err.clone()))).collect(),
+            };
+            for err in results.iter().filter_map(|r| r.as_ref().err()) {
+                eprintln!("node {}: cannot apply entry {}: {}", self.id, entry.index, err);
+            }
+            for (p, result) in proposals.into_iter().zip(results) {
+                // the proposer may have given up waiting
+                let _ = p.propose_success.send(result);
+            }
+        }
+        if self.saved_index != self.applied_index {
+            self.save_applied_index();
+        }
+
+        let mut snapshot = None;
+        if self.compaction.should_compact(self.applied_entries, self.applied_bytes) {
+            match self.state_machine.snapshot() {
+                Ok(data) => {
+                    snapshot = Some((conf_state, data));
+                    self.applied_entries = 0;
+                    self.applied_bytes = 0;
+                }
+                Err(e) => eprintln!("node {}: snapshot state machine fail: {}", self.id, e),
+            }
+        }
+        ApplyResult { applied_index: self.applied_index, snapshot }
+    }
+
+    // the applied index only moves to the snapshot once the state machine holds it
+    fn restore(&mut self, snapshot: Snapshot) -> Result<ApplyResult, String> {
+        let index = snapshot.get_metadata().index;
+        self.state_machine.restore(&snapshot.data)
+            .map_err(|e| format!("node {}: cannot restore snapshot {}: {}", self.id, index, e))?;
+        self.applied_index = index;
+        self.applied_entries = 0;
+        self.applied_bytes = 0;
+        self.save_applied_index();
+        Ok(ApplyResult { applied_index: self.applied_index, snapshot: None })
+    }
+
+    fn save_applied_index(&mut self) {
+        match self.state_machine.set_applied_index(self.applied_index) {
+            Ok(_) => self.saved_index = self.applied_index,
+            Err(e) => eprintln!("node {}: save applied index fail: {}", self.id, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{Parameters, Request, Response, Statement};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct CountStateMachine {
+        applied: usize,
+    }
+
+    impl StateMachine for CountStateMachine {
+        fn apply(&mut self, req: &Request) -> Result<Vec<Response>, String> {
+            self.applied += 1;
+            Ok(req.statements.iter().map(|_| Response {
+                last_insert_id: self.applied as i64,
+                rows_affected: 1,
+                error: String::new(),
+            }).collect())
+        }
+
+        fn snapshot(&self) -> Result<Vec<u8>, String> {
+            Ok(vec![self.applied as u8])
+        }
+
+        fn restore(&mut self, data: &[u8]) -> Result<(), String> {
+            self.applied = *data.first().ok_or("empty snapshot")? as usize;
+            Ok(())
+        }
+    }
+
+    fn entry(index: u64, requests: usize) -> Entry {
+        let mut batch = RequestBatch::new();
+        for _ in 0..requests {
+            batch.push(&Request {
+                transaction: false,
+                statements: Box::new([Statement { sql: String::from("DELETE FROM foo"), parameters: Parameters::default() }]),
+            });
+        }
+        let mut entry = Entry::default();
+        entry.index = index;
+        if requests > 0 {
+            entry.data = batch.encode();
+        }
+        entry
+    }
+
+    fn applied(mailbox: &Receiver<PeerMsg>) -> ApplyResult {
+        match mailbox.recv_timeout(Duration::from_secs(1)).unwrap() {
+            PeerMsg::Applied(result) => result,
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_apply_worker() {
+        let (tx, mailbox) = mpsc::channel();
+        let compaction = CompactionConfig { snapshot_entries: 3, snapshot_bytes: 0 };
+        let worker = ApplyWorker::start(1, CountStateMachine::default(), 0, compaction, tx);
+
+        let (p1, rx1) = Proposal::normal(Request { transaction: false, statements: Box::new([]) });
+        let (p2, rx2) = Proposal::normal(Request { transaction: false, statements: Box::new([]) });
+        // entry 2 is the empty entry of a new leader
+        let entries = vec![(entry(1, 2), vec![p1, p2]), (entry(2, 0), vec![])];
+        worker.schedule(ApplyTask::Apply { entries, conf_state: ConfState::default() });
+
+        let result = applied(&mailbox);
+        assert_eq!(result.applied_index, 2);
+        assert!(result.snapshot.is_none());
+        // each proposal of the batch gets the response of its own request
+        assert_eq!(rx1.recv().unwrap().unwrap()[0].last_insert_id, 1);
+        assert_eq!(rx2.recv().unwrap().unwrap()[0].last_insert_id, 2);
+
+        // the third entry reaches the compaction threshold
+        worker.schedule(ApplyTask::Apply { entries: vec![(entry(3, 1), vec![])], conf_state: ConfState::default() });
+        let result = applied(&mailbox);
+        assert_eq!(result.applied_index, 3);
+        assert_eq!(result.snapshot.unwrap().1, vec![3]);
+
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 10;
+        snapshot.data = vec![7];
+        worker.schedule(ApplyTask::Restore(snapshot));
+        assert_eq!(applied(&mailbox).applied_index, 10);
+
+        // a snapshot which can't be restored stops the worker, the entries after it aren't applied
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 20;
+        worker.schedule(ApplyTask::Restore(snapshot));
+        worker.schedule(ApplyTask::Apply { entries: vec![(entry(21, 1), vec![])], conf_state: ConfState::default() });
+        match mailbox.recv_timeout(Duration::from_secs(1)).unwrap() {
+            PeerMsg::ApplyFailed(err) => assert_eq!(err, "node 1: cannot restore snapshot 20: empty snapshot"),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert!(mailbox.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use serde::Deserialize;
+
+// CompactionConfig decides when the log is compacted: the state machine is snapshotted
+// once the entries applied since the last snapshot reach any of the thresholds. 0 disables a threshold.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompactionConfig {
+    // number of entries applied since the last snapshot
+    pub snapshot_entries: u64,
+    // size in bytes of the entries applied since the last snapshot
+    pub snapshot_bytes: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        CompactionConfig {
+            snapshot_entries: 8192,
+            snapshot_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl CompactionConfig {
+    pub fn should_compact(&self, applied_entries: u64, applied_bytes: u64) -> bool {
+        (self.snapshot_entries > 0 && applied_entries >= self.snapshot_entries)
+            || (self.snapshot_bytes > 0 && applied_bytes >= self.snapshot_bytes)
+    }
+}
+
+// LogMetrics describes the Raft log and its latest compaction
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LogMetrics {
+    // number of compactions since the node started
+    pub compactions: u64,
+    // applied index recorded by the latest snapshot
+    pub snapshot_index: u64,
+    // entries and bytes of the log right before the latest compaction
+    pub entries_before: u64,
+    pub bytes_before: u64,
+    // entries and bytes of the log right after the latest compaction
+    pub entries_after: u64,
+    pub bytes_after: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_compact() {
+        let config = CompactionConfig { snapshot_entries: 10, snapshot_bytes: 100 };
+        assert!(!config.should_compact(9, 99));
+        assert!(config.should_compact(10, 0));
+        assert!(config.should_compact(0, 100));
+
+        let entries_only = CompactionConfig { snapshot_entries: 10, snapshot_bytes: 0 };
+        assert!(!entries_only.should_compact(9, u64::MAX));
+        assert!(entries_only.should_compact(10, 0));
+
+        let disabled = CompactionConfig { snapshot_entries: 0, snapshot_bytes: 0 };
+        assert!(!disabled.should_compact(u64::MAX, u64::MAX));
+    }
+}
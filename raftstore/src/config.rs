@@ -0,0 +1,170 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use raft::{Config, ReadOnlyOption};
+use serde::Deserialize;
+use crate::compaction::CompactionConfig;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("cannot read config file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("cannot parse config file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error(transparent)]
+    Invalid(#[from] raft::Error),
+}
+
+// RaftConfig is the configuration of the Raft node. Timeouts are counted in ticks of tick_interval_ms.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RaftConfig {
+    // time between two ticks of the Raft node
+    pub tick_interval_ms: u64,
+    // a follower which doesn't hear from the leader for election_tick ticks starts an election
+    pub election_tick: usize,
+    // the leader sends heartbeats every heartbeat_tick ticks
+    pub heartbeat_tick: usize,
+    // a node only starts an election once a quorum would vote for it, so a partitioned node doesn't disrupt the cluster
+    pub pre_vote: bool,
+    // the leader steps down when it doesn't hear from a quorum within an election timeout
+    pub check_quorum: bool,
+    // the leader serves reads under its lease, it requires check_quorum
+    pub lease_read: bool,
+    // maximum size in bytes of the entries of an append message, 0 sends a single entry per message
+    pub max_size_per_msg: u64,
+    // maximum number of append messages in flight to a follower
+    pub max_inflight_msgs: usize,
+    // the leader doesn't broadcast the commit index in its own message, followers learn it from the next append
+    pub skip_bcast_commit: bool,
+    // the leader proposes the writes queued together as a single entry of at most max_batch_requests
+    // requests, it's closed once its size reaches max_batch_bytes
+    pub max_batch_requests: usize,
+    pub max_batch_bytes: u64,
+    pub compaction: CompactionConfig,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            tick_interval_ms: 100,
+            election_tick: 10,
+            heartbeat_tick: 3,
+            pre_vote: false,
+            check_quorum: false,
+            lease_read: false,
+            max_size_per_msg: 0,
+            max_inflight_msgs: 256,
+            skip_bcast_commit: false,
+            max_batch_requests: 256,
+            max_batch_bytes: 1024 * 1024,
+            compaction: CompactionConfig::default(),
+        }
+    }
+}
+
+impl RaftConfig {
+    // loads the `[raft]` table of the node's config file, missing settings take their default value
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<RaftConfig, ConfigError> {
+        RaftConfig::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_toml(s: &str) -> Result<RaftConfig, ConfigError> {
+        #[derive(Deserialize, Default)]
+        struct ConfigFile {
+            #[serde(default)]
+            raft: RaftConfig,
+        }
+
+        let config = toml::from_str::<ConfigFile>(s)?.raft;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_millis(self.tick_interval_ms)
+    }
+
+    pub fn validate(&self) -> raft::Result<()> {
+        if self.tick_interval_ms == 0 {
+            return Err(raft::Error::ConfigInvalid(String::from("tick interval must be greater than 0")));
+        }
+        if self.max_batch_requests == 0 || self.max_batch_bytes == 0 {
+            return Err(raft::Error::ConfigInvalid(String::from("batches must hold at least 1 request")));
+        }
+        if self.lease_read && !self.check_quorum {
+            return Err(raft::Error::ConfigInvalid(String::from("lease read requires check quorum")));
+        }
+        self.raft_config(1, 0).validate()
+    }
+
+    // the configuration of the raft node with the given id. The entries up to applied, recovered from the
+    // state machine, aren't applied again
+    pub fn raft_config(&self, id: u64, applied: u64) -> Config {
+        let read_only_option = if self.lease_read { ReadOnlyOption::LeaseBased } else { ReadOnlyOption::Safe };
+        Config {
+            id,
+            election_tick: self.election_tick,
+            heartbeat_tick: self.heartbeat_tick,
+            pre_vote: self.pre_vote,
+            check_quorum: self.check_quorum,
+            read_only_option,
+            max_size_per_msg: self.max_size_per_msg,
+            max_inflight_msgs: self.max_inflight_msgs,
+            applied,
+            skip_bcast_commit: self.skip_bcast_commit,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_toml() {
+        let config = RaftConfig::from_toml(r#"
+            [node]
+            id = 1
+
+            [raft]
+            tick_interval_ms = 50
+            election_tick = 20
+            check_quorum = true
+            lease_read = true
+
+            [raft.compaction]
+            snapshot_entries = 100
+        "#).unwrap();
+        assert_eq!(config.tick_interval(), Duration::from_millis(50));
+        assert_eq!(config.election_tick, 20);
+        assert_eq!(config.heartbeat_tick, 3);
+        assert_eq!(config.raft_config(2, 0).read_only_option, ReadOnlyOption::LeaseBased);
+        assert_eq!(config.compaction.snapshot_entries, 100);
+        assert_eq!(config.compaction.snapshot_bytes, CompactionConfig::default().snapshot_bytes);
+
+        assert!(RaftConfig::from_toml("").is_ok());
+        assert!(matches!(RaftConfig::from_toml("[raft]\nelection_ticks = 20"), Err(ConfigError::Parse(_))));
+        // the applied index is recovered from the state machine, it can't be set
+        assert!(matches!(RaftConfig::from_toml("[raft]\napplied = 5"), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(RaftConfig::default().validate().is_ok());
+
+        let invalid = vec![
+            RaftConfig { tick_interval_ms: 0, ..Default::default() },
+            RaftConfig { lease_read: true, ..Default::default() },
+            RaftConfig { heartbeat_tick: 0, ..Default::default() },
+            RaftConfig { election_tick: 3, heartbeat_tick: 3, ..Default::default() },
+            RaftConfig { max_inflight_msgs: 0, ..Default::default() },
+            RaftConfig { max_batch_requests: 0, ..Default::default() },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+}
@@ -0,0 +1,800 @@
+use std::cmp;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use dust_util::box_err;
+use protobuf::Message as PbMessage;
+use raft::{prelude::*, StorageError};
+
+use crate::raft_storage::RaftStorage;
+
+// directory holding all log segments
+const LOG_DIR: &str = "log";
+// file holding the hard state and the conf state
+const RAFT_STATE_FILE: &str = "raft_state";
+// file holding the latest snapshot
+const SNAPSHOT_FILE: &str = "snapshot";
+const SEGMENT_EXTENSION: &str = "log";
+
+// a new segment is started when the current one grows over this size
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+// every record starts with the length of its payload and the crc32 of the payload, both are u32
+const HEADER_SIZE: usize = 8;
+
+// Segment is an append-only file of entry records, named after the index of its first entry
+struct Segment {
+    first_index: u64,
+    path: PathBuf,
+    file: File,
+    // file offset of every entry, offsets[i] is the offset of the entry first_index + i
+    offsets: Vec<u64>,
+    size: u64,
+}
+
+impl Segment {
+    fn create(dir: &Path, first_index: u64) -> io::Result<Segment> {
+        let path = dir.join(format!("{:020}.{}", first_index, SEGMENT_EXTENSION));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        sync_dir(dir)?;
+        Ok(Segment { first_index, path, file, offsets: Vec::new(), size: 0 })
+    }
+
+    fn last_index(&self) -> u64 {
+        self.first_index + self.offsets.len() as u64 - 1
+    }
+
+    fn write(&mut self, entry: &Entry) -> raft::Result<()> {
+        let record = encode_record(&entry.write_to_bytes()?);
+        self.file.write_all(&record)?;
+        self.offsets.push(self.size);
+        self.size += record.len() as u64;
+        Ok(())
+    }
+}
+
+// DiskStorage is a durable raft::Storage.
+// Entries are written to segmented append-only files, the hard state and the conf state are kept in a separated file.
+// All entries after the latest snapshot are also cached in memory to serve reads.
+pub struct DiskStorage {
+    dir: PathBuf,
+    hard_state: HardState,
+    conf_state: ConfState,
+    // configuration after the membership change in progress, with the index of the entry which started it
+    pending_conf_state: Option<(ConfState, u64)>,
+    // all entries up to the index of the snapshot are discarded from the log
+    snapshot: Snapshot,
+    // entries[i] has the index first_index() + i
+    entries: Vec<Entry>,
+    segments: Vec<Segment>,
+}
+
+impl DiskStorage {
+    // opens the storage under dir, creating it if it does not exist.
+    // A torn record at the end of the last segment (e.g.: crashed while writing) is discarded.
+    pub fn open<P: AsRef<Path>>(dir: P) -> raft::Result<DiskStorage> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join(LOG_DIR))?;
+
+        let mut storage = DiskStorage {
+            dir,
+            hard_state: HardState::default(),
+            conf_state: ConfState::default(),
+            pending_conf_state: None,
+            snapshot: Snapshot::default(),
+            entries: Vec::new(),
+            segments: Vec::new(),
+        };
+        storage.load_snapshot()?;
+        storage.load_raft_state()?;
+        // the snapshot file is written first by apply_snapshot: a raft state behind it wasn't saved before a crash
+        if storage.hard_state.commit < storage.snapshot_index() {
+            storage.set_snapshot_state();
+            storage.save_raft_state()?;
+        }
+        storage.load_segments()?;
+        Ok(storage)
+    }
+
+    fn log_dir(&self) -> PathBuf {
+        self.dir.join(LOG_DIR)
+    }
+
+    fn snapshot_index(&self) -> u64 {
+        self.snapshot.get_metadata().index
+    }
+
+    fn load_snapshot(&mut self) -> raft::Result<()> {
+        if let Some(payload) = read_record_file(&self.dir.join(SNAPSHOT_FILE))? {
+            self.snapshot.merge_from_bytes(&payload)?;
+        }
+        Ok(())
+    }
+
+    // the hard state and the conf state once the snapshot is applied
+    fn set_snapshot_state(&mut self) {
+        let metadata = self.snapshot.get_metadata();
+        self.hard_state.term = cmp::max(self.hard_state.term, metadata.term);
+        self.hard_state.commit = metadata.index;
+        self.conf_state = metadata.get_conf_state().clone();
+        self.pending_conf_state = if metadata.has_pending_membership_change() {
+            Some((metadata.get_pending_membership_change().clone(), metadata.pending_membership_change_index))
+        } else {
+            None
+        };
+    }
+
+    fn load_raft_state(&mut self) -> raft::Result<()> {
+        let payload = match read_record_file(&self.dir.join(RAFT_STATE_FILE))? {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+
+        let mut buf = payload.as_slice();
+        let hs = get_bytes(&mut buf).ok_or_else(|| corrupted("raft state: missing hard state"))?;
+        let cs = get_bytes(&mut buf).ok_or_else(|| corrupted("raft state: missing conf state"))?;
+        self.hard_state.merge_from_bytes(hs)?;
+        self.conf_state.merge_from_bytes(cs)?;
+
+        // the pending conf state and its start index follow only during a membership change
+        if let Some(pending) = get_bytes(&mut buf) {
+            let start_index = get_bytes(&mut buf)
+                .and_then(|index| index.try_into().ok())
+                .ok_or_else(|| corrupted("raft state: missing start index of the pending conf state"))?;
+            let mut cs = ConfState::default();
+            cs.merge_from_bytes(pending)?;
+            self.pending_conf_state = Some((cs, u64::from_le_bytes(start_index)));
+        }
+        Ok(())
+    }
+
+    fn save_raft_state(&self) -> raft::Result<()> {
+        let mut payload = Vec::new();
+        put_bytes(&mut payload, &self.hard_state.write_to_bytes()?);
+        put_bytes(&mut payload, &self.conf_state.write_to_bytes()?);
+        if let Some((ref cs, start_index)) = self.pending_conf_state {
+            put_bytes(&mut payload, &cs.write_to_bytes()?);
+            put_bytes(&mut payload, &start_index.to_le_bytes());
+        }
+        write_record_file(&self.dir.join(RAFT_STATE_FILE), &payload)?;
+        Ok(())
+    }
+
+    // replays all segments in order of their first index.
+    // The log continues the snapshot only when it holds the entry of the snapshot, with the same term: the segments
+    // left by a crash in apply_snapshot belong to the previous log and are removed
+    fn load_segments(&mut self) -> raft::Result<()> {
+        let mut paths = Vec::new();
+        for dir_entry in fs::read_dir(self.log_dir())? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let first_index = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+                .ok_or_else(|| corrupted(format!("invalid segment name: {:?}", path)))?;
+            paths.push((first_index, path));
+        }
+        paths.sort();
+
+        let mut follows_snapshot = self.snapshot_index() == 0;
+        let count = paths.len();
+        for (i, (first_index, path)) in paths.into_iter().enumerate() {
+            let is_last = i == count - 1;
+            if let Some(last) = self.segments.last() {
+                if first_index != last.last_index() + 1 {
+                    return Err(corrupted(format!("segment {:?} does not follow the previous segment", path)));
+                }
+            }
+
+            let buf = fs::read(&path)?;
+            let mut offsets = Vec::new();
+            let mut offset = 0;
+            while offset < buf.len() {
+                let payload = match decode_record(&buf[offset..]) {
+                    Some(payload) => payload,
+                    None if is_last => {
+                        eprintln!("discard torn record at {} of segment {:?}", offset, path);
+                        break;
+                    }
+                    None => return Err(corrupted(format!("invalid record at {} of segment {:?}", offset, path))),
+                };
+
+                let mut entry = Entry::default();
+                entry.merge_from_bytes(payload)?;
+                if entry.index != first_index + offsets.len() as u64 {
+                    return Err(corrupted(format!("unexpected index {} in segment {:?}", entry.index, path)));
+                }
+
+                if entry.index == self.snapshot_index() {
+                    follows_snapshot = entry.term == self.snapshot.get_metadata().term;
+                }
+                // entries covered by the snapshot stay in the file until the whole segment is removed
+                if entry.index > self.snapshot_index() && follows_snapshot {
+                    if entry.index != self.last_index_() + 1 {
+                        return Err(corrupted(format!("missing entries before {} in segment {:?}", entry.index, path)));
+                    }
+                    self.entries.push(entry);
+                }
+                offsets.push(offset as u64);
+                offset += HEADER_SIZE + payload.len();
+            }
+
+            let file = OpenOptions::new().append(true).open(&path)?;
+            if offset < buf.len() {
+                file.set_len(offset as u64)?;
+                file.sync_all()?;
+            }
+            self.segments.push(Segment { first_index, path, file, offsets, size: offset as u64 });
+        }
+
+        // an empty segment is not useful: the next append creates a new one at the right index
+        if let Some(segment) = self.segments.last() {
+            if segment.offsets.is_empty() {
+                fs::remove_file(&segment.path)?;
+                self.segments.pop();
+            }
+        }
+
+        if !follows_snapshot && !self.segments.is_empty() {
+            eprintln!("discard the log before snapshot {}", self.snapshot_index());
+            for segment in self.segments.drain(..) {
+                fs::remove_file(&segment.path)?;
+            }
+            sync_dir(&self.log_dir())?;
+        }
+        Ok(())
+    }
+
+    fn first_index_(&self) -> u64 {
+        self.snapshot_index() + 1
+    }
+
+    fn last_index_(&self) -> u64 {
+        self.snapshot_index() + self.entries.len() as u64
+    }
+
+    // removes every entry from index onward, in memory and on disk
+    fn truncate(&mut self, index: u64) -> raft::Result<()> {
+        if index > self.last_index_() {
+            return Ok(());
+        }
+        self.entries.truncate((index - self.first_index_()) as usize);
+
+        let mut removed = false;
+        while let Some(segment) = self.segments.last_mut() {
+            if segment.first_index >= index {
+                fs::remove_file(&segment.path)?;
+                self.segments.pop();
+                removed = true;
+                continue;
+            }
+
+            let kept = (index - segment.first_index) as usize;
+            if kept < segment.offsets.len() {
+                let offset = segment.offsets[kept];
+                segment.file.set_len(offset)?;
+                segment.file.sync_all()?;
+                segment.offsets.truncate(kept);
+                segment.size = offset;
+            }
+            break;
+        }
+
+        if removed {
+            sync_dir(&self.log_dir())?;
+        }
+        Ok(())
+    }
+
+    fn write_entry(&mut self, entry: &Entry) -> raft::Result<()> {
+        if self.segments.is_empty() && self.snapshot_index() > 0 {
+            // the log after a snapshot starts with the entry of the snapshot, without its data
+            let first = Entry { index: self.snapshot_index(), term: self.snapshot.get_metadata().term, ..Default::default() };
+            let mut segment = Segment::create(&self.log_dir(), first.index)?;
+            segment.write(&first)?;
+            self.segments.push(segment);
+        }
+
+        let full = match self.segments.last() {
+            Some(segment) => segment.size >= SEGMENT_SIZE,
+            None => true,
+        };
+        if full {
+            if let Some(segment) = self.segments.last_mut() {
+                segment.file.sync_data()?;
+            }
+            let segment = Segment::create(&self.log_dir(), entry.index)?;
+            self.segments.push(segment);
+        }
+
+        self.segments.last_mut().unwrap().write(entry)?;
+        self.entries.push(entry.clone());
+        Ok(())
+    }
+}
+
+impl Storage for DiskStorage {
+    fn initial_state(&self) -> raft::Result<RaftState> {
+        let mut state = RaftState::new(self.hard_state.clone(), self.conf_state.clone());
+        if let Some((ref cs, start_index)) = self.pending_conf_state {
+            state.pending_conf_state = Some(cs.clone());
+            state.pending_conf_state_start_index = Some(start_index);
+        }
+        Ok(state)
+    }
+
+    fn entries(&self, low: u64, high: u64, max_size: impl Into<Option<u64>>) -> raft::Result<Vec<Entry>> {
+        if low < self.first_index_() {
+            return Err(raft::Error::Store(StorageError::Compacted));
+        }
+        if high > self.last_index_() + 1 {
+            panic!("index out of bound (last: {}, high: {})", self.last_index_() + 1, high);
+        }
+
+        let offset = self.first_index_();
+        let entries = &self.entries[(low - offset) as usize..(high - offset) as usize];
+
+        // always return at least one entry even if it is larger than max_size
+        let max_size = max_size.into().unwrap_or(u64::MAX);
+        let mut size = 0;
+        let mut result = Vec::new();
+        for entry in entries {
+            size += u64::from(entry.compute_size());
+            if !result.is_empty() && size > max_size {
+                break;
+            }
+            result.push(entry.clone());
+        }
+        Ok(result)
+    }
+
+    fn term(&self, idx: u64) -> raft::Result<u64> {
+        if idx == self.snapshot_index() {
+            return Ok(self.snapshot.get_metadata().term);
+        }
+        if idx < self.first_index_() {
+            return Err(raft::Error::Store(StorageError::Compacted));
+        }
+        if idx > self.last_index_() {
+            return Err(raft::Error::Store(StorageError::Unavailable));
+        }
+        Ok(self.entries[(idx - self.first_index_()) as usize].term)
+    }
+
+    fn first_index(&self) -> raft::Result<u64> {
+        Ok(self.first_index_())
+    }
+
+    fn last_index(&self) -> raft::Result<u64> {
+        Ok(self.last_index_())
+    }
+
+    fn snapshot(&self) -> raft::Result<Snapshot> {
+        Ok(self.snapshot.clone())
+    }
+}
+
+impl RaftStorage for DiskStorage {
+    fn append(&mut self, entries: &[Entry]) -> raft::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let first = entries[0].index;
+        if self.first_index_() > first {
+            panic!("overwrite compacted raft logs, compacted: {}, append: {}", self.first_index_() - 1, first);
+        }
+        if self.last_index_() + 1 < first {
+            panic!("raft logs should be continuous, last index: {}, new appended: {}", self.last_index_(), first);
+        }
+
+        self.truncate(first)?;
+        for entry in entries {
+            self.write_entry(entry)?;
+        }
+        self.segments.last_mut().unwrap().file.sync_data()?;
+        Ok(())
+    }
+
+    fn apply_snapshot(&mut self, snapshot: Snapshot) -> raft::Result<()> {
+        let metadata = snapshot.get_metadata();
+        if metadata.index <= self.snapshot_index() {
+            return Err(raft::Error::Store(StorageError::SnapshotOutOfDate));
+        }
+
+        // the snapshot is saved first, then the raft state which follows it. The previous log is removed last:
+        // when reopening after a crash, both are recovered from the snapshot and what's left of the log is discarded
+        write_record_file(&self.dir.join(SNAPSHOT_FILE), &snapshot.write_to_bytes()?)?;
+        self.snapshot = snapshot;
+        self.set_snapshot_state();
+        self.save_raft_state()?;
+
+        self.entries.clear();
+        for segment in self.segments.drain(..) {
+            fs::remove_file(&segment.path)?;
+        }
+        sync_dir(&self.log_dir())?;
+        Ok(())
+    }
+
+    fn set_hard_state(&mut self, hs: HardState) -> raft::Result<()> {
+        self.hard_state = hs;
+        self.save_raft_state()
+    }
+
+    fn set_conf_state(&mut self, cs: ConfState, pending_membership_change: Option<(ConfState, u64)>) -> raft::Result<()> {
+        self.conf_state = cs;
+        self.pending_conf_state = pending_membership_change;
+        self.save_raft_state()
+    }
+
+    fn create_snapshot(&mut self, index: u64, cs: ConfState, data: Vec<u8>) -> raft::Result<()> {
+        if index <= self.snapshot_index() {
+            return Err(raft::Error::Store(StorageError::SnapshotOutOfDate));
+        }
+        if index > self.last_index_() {
+            panic!("snapshot after the last entry (last: {}, snapshot: {})", self.last_index_(), index);
+        }
+
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = index;
+        snapshot.mut_metadata().term = self.term(index)?;
+        snapshot.mut_metadata().set_conf_state(cs);
+        // a change started before the snapshot is still pending for the nodes restoring it
+        if let Some((ref pending, start_index)) = self.pending_conf_state {
+            if start_index <= index {
+                snapshot.mut_metadata().set_pending_membership_change(pending.clone());
+                snapshot.mut_metadata().pending_membership_change_index = start_index;
+            }
+        }
+        snapshot.data = data;
+        write_record_file(&self.dir.join(SNAPSHOT_FILE), &snapshot.write_to_bytes()?)?;
+
+        self.entries.drain(..(index - self.first_index_() + 1) as usize);
+        self.snapshot = snapshot;
+
+        // segments holding only entries before the snapshot are removed, the others are skipped when reopening.
+        // The entry of the snapshot stays on disk, like the first entry of a log after a snapshot
+        let covered = self.segments.iter().take_while(|segment| segment.last_index() < index).count();
+        for segment in self.segments.drain(..covered) {
+            fs::remove_file(&segment.path)?;
+        }
+        if covered > 0 {
+            sync_dir(&self.log_dir())?;
+        }
+        Ok(())
+    }
+
+    // segments partly covered by the snapshot are counted as a whole, as they stay on disk
+    fn log_bytes(&self) -> raft::Result<u64> {
+        Ok(self.segments.iter().map(|segment| segment.size).sum())
+    }
+}
+
+fn corrupted<E: Into<String>>(msg: E) -> raft::Error {
+    raft::Error::Store(StorageError::Other(box_err!("corrupted raft storage: {}", msg.into())))
+}
+
+// encodes payload as a record: length, crc32 then the payload itself
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+// returns the payload of the record at the beginning of buf, None if the record is incomplete or its checksum mismatches
+fn decode_record(buf: &[u8]) -> Option<&[u8]> {
+    if buf.len() < HEADER_SIZE {
+        return None;
+    }
+    let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    if buf.len() - HEADER_SIZE < len {
+        return None;
+    }
+
+    let payload = &buf[HEADER_SIZE..HEADER_SIZE + len];
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+    Some(payload)
+}
+
+// atomically replaces the file at path by a single record: write to a temporary file then rename it
+fn write_record_file(path: &Path, payload: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&encode_record(payload))?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path.parent().unwrap())
+}
+
+fn read_record_file(path: &Path) -> raft::Result<Option<Vec<u8>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let buf = fs::read(path)?;
+    match decode_record(&buf) {
+        Some(payload) => Ok(Some(payload.to_vec())),
+        None => Err(corrupted(format!("invalid record in {:?}", path))),
+    }
+}
+
+// persists the creation, removal or renaming of files inside dir
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn get_bytes<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    if buf.len() - 4 < len {
+        return None;
+    }
+    let bytes = &buf[4..4 + len];
+    *buf = &buf[4 + len..];
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::default();
+        e.index = index;
+        e.term = term;
+        e.data = format!("entry {}", index).into_bytes();
+        e
+    }
+
+    #[test]
+    fn test_append_and_reopen() {
+        let dir = TempDir::new().unwrap();
+
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.first_index().unwrap(), 1);
+        assert_eq!(storage.last_index().unwrap(), 0);
+
+        let entries: Vec<Entry> = (1..6).map(|i| new_entry(i, 1)).collect();
+        storage.append(&entries).unwrap();
+
+        let mut hs = HardState::default();
+        hs.term = 1;
+        hs.vote = 2;
+        hs.commit = 4;
+        storage.set_hard_state(hs.clone()).unwrap();
+
+        let mut cs = ConfState::default();
+        cs.nodes = vec![1, 2, 3];
+        storage.set_conf_state(cs.clone(), None).unwrap();
+        drop(storage);
+
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.first_index().unwrap(), 1);
+        assert_eq!(storage.last_index().unwrap(), 5);
+        assert_eq!(storage.entries(1, 6, None).unwrap(), entries);
+        assert_eq!(storage.term(3).unwrap(), 1);
+
+        let state = storage.initial_state().unwrap();
+        assert_eq!(state.hard_state, hs);
+        assert_eq!(state.conf_state, cs);
+        assert_eq!(state.pending_conf_state, None);
+    }
+
+    #[test]
+    fn test_pending_conf_state() {
+        let dir = TempDir::new().unwrap();
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+
+        // the joint configuration of a membership change started at index 7 survives a restart
+        let mut cs = ConfState::default();
+        cs.nodes = vec![1, 2, 3];
+        let mut pending = ConfState::default();
+        pending.nodes = vec![1, 3, 4];
+        storage.set_conf_state(cs.clone(), Some((pending.clone(), 7))).unwrap();
+        drop(storage);
+
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        let state = storage.initial_state().unwrap();
+        assert_eq!(state.conf_state, cs);
+        assert_eq!(state.pending_conf_state, Some(pending.clone()));
+        assert_eq!(state.pending_conf_state_start_index, Some(7));
+
+        // once the change is finalized, the new configuration is the only one
+        storage.set_conf_state(pending.clone(), None).unwrap();
+        drop(storage);
+        let state = DiskStorage::open(dir.path()).unwrap().initial_state().unwrap();
+        assert_eq!(state.conf_state, pending);
+        assert_eq!(state.pending_conf_state, None);
+        assert_eq!(state.pending_conf_state_start_index, None);
+    }
+
+    #[test]
+    fn test_append_overwrite() {
+        let dir = TempDir::new().unwrap();
+
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        storage.append(&(1..6).map(|i| new_entry(i, 1)).collect::<Vec<_>>()).unwrap();
+        // a new leader replaces the entries from index 3
+        storage.append(&[new_entry(3, 2), new_entry(4, 2)]).unwrap();
+        assert_eq!(storage.last_index().unwrap(), 4);
+        drop(storage);
+
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.last_index().unwrap(), 4);
+        assert_eq!(storage.term(2).unwrap(), 1);
+        assert_eq!(storage.term(3).unwrap(), 2);
+        assert_eq!(storage.term(4).unwrap(), 2);
+
+        storage.append(&[new_entry(5, 2)]).unwrap();
+        drop(storage);
+
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.last_index().unwrap(), 5);
+        assert_eq!(storage.term(5).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_entries_max_size() {
+        let dir = TempDir::new().unwrap();
+
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        storage.append(&(1..6).map(|i| new_entry(i, 1)).collect::<Vec<_>>()).unwrap();
+
+        let size = u64::from(new_entry(1, 1).compute_size());
+        assert_eq!(storage.entries(1, 6, size * 2).unwrap().len(), 2);
+        // at least one entry is returned
+        assert_eq!(storage.entries(1, 6, 0).unwrap().len(), 1);
+        assert!(storage.entries(0, 2, None).is_err());
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let dir = TempDir::new().unwrap();
+
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        storage.append(&(1..4).map(|i| new_entry(i, 1)).collect::<Vec<_>>()).unwrap();
+        let path = storage.segments[0].path.clone();
+        drop(storage);
+
+        // simulate a crash in the middle of writing the last record
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.last_index().unwrap(), 2);
+
+        storage.append(&[new_entry(3, 2)]).unwrap();
+        drop(storage);
+
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.last_index().unwrap(), 3);
+        assert_eq!(storage.term(3).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_apply_snapshot() {
+        let dir = TempDir::new().unwrap();
+
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        storage.append(&(1..4).map(|i| new_entry(i, 1)).collect::<Vec<_>>()).unwrap();
+
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 10;
+        snapshot.mut_metadata().term = 3;
+        snapshot.mut_metadata().mut_conf_state().nodes = vec![1, 2];
+        storage.apply_snapshot(snapshot.clone()).unwrap();
+        storage.append(&[new_entry(11, 3)]).unwrap();
+        drop(storage);
+
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.first_index().unwrap(), 11);
+        assert_eq!(storage.last_index().unwrap(), 11);
+        assert_eq!(storage.term(10).unwrap(), 3);
+        assert_eq!(storage.snapshot().unwrap(), snapshot);
+        assert_eq!(storage.initial_state().unwrap().hard_state.commit, 10);
+        assert_eq!(storage.initial_state().unwrap().conf_state.nodes, vec![1, 2]);
+
+        // an older snapshot is rejected
+        assert!(storage.apply_snapshot(snapshot).is_err());
+    }
+
+    // reopens the storage as if apply_snapshot crashed once the snapshot file was written, then once the
+    // raft state was saved too, which leaves the segments of the previous log on disk
+    #[test]
+    fn test_apply_snapshot_crash() {
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 10;
+        snapshot.mut_metadata().term = 3;
+        snapshot.mut_metadata().mut_conf_state().nodes = vec![1, 2];
+
+        let dir = TempDir::new().unwrap();
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        storage.append(&(1..13).map(|i| new_entry(i, 1)).collect::<Vec<_>>()).unwrap();
+        storage.set_hard_state(HardState { term: 1, commit: 5, ..Default::default() }).unwrap();
+        drop(storage);
+        let segments: Vec<_> = fs::read_dir(dir.path().join(LOG_DIR)).unwrap()
+            .map(|dir_entry| {
+                let path = dir_entry.unwrap().path();
+                let data = fs::read(&path).unwrap();
+                (path, data)
+            })
+            .collect();
+
+        write_record_file(&dir.path().join(SNAPSHOT_FILE), &snapshot.write_to_bytes().unwrap()).unwrap();
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        let state = storage.initial_state().unwrap();
+        assert_eq!(state.hard_state.term, 3);
+        assert_eq!(state.hard_state.commit, 10);
+        assert_eq!(state.conf_state.nodes, vec![1, 2]);
+        // the entries after the snapshot don't follow it, their term at index 10 is 1
+        assert_eq!(storage.first_index().unwrap(), 11);
+        assert_eq!(storage.last_index().unwrap(), 10);
+        assert_eq!(fs::read_dir(dir.path().join(LOG_DIR)).unwrap().count(), 0);
+        drop(storage);
+
+        for (path, data) in &segments {
+            fs::write(path, data).unwrap();
+        }
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.initial_state().unwrap().hard_state.commit, 10);
+        assert_eq!(storage.first_index().unwrap(), 11);
+        assert_eq!(storage.last_index().unwrap(), 10);
+        assert_eq!(storage.snapshot().unwrap(), snapshot);
+        storage.append(&[new_entry(11, 3)]).unwrap();
+        drop(storage);
+
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.entries(11, 12, None).unwrap(), vec![new_entry(11, 3)]);
+    }
+
+    #[test]
+    fn test_create_snapshot() {
+        let dir = TempDir::new().unwrap();
+
+        let mut storage = DiskStorage::open(dir.path()).unwrap();
+        storage.append(&(1..4).map(|i| new_entry(i, 1)).collect::<Vec<_>>()).unwrap();
+        // start a second segment
+        storage.segments[0].size = SEGMENT_SIZE;
+        storage.append(&(4..7).map(|i| new_entry(i, 2)).collect::<Vec<_>>()).unwrap();
+        assert_eq!(storage.segments.len(), 2);
+
+        let mut cs = ConfState::default();
+        cs.nodes = vec![1, 2, 3];
+        storage.create_snapshot(4, cs.clone(), b"state machine".to_vec()).unwrap();
+        // only the first segment is fully covered by the snapshot
+        let size = storage.log_bytes().unwrap();
+        assert_eq!(storage.segments.len(), 1);
+        assert_eq!(size, storage.segments[0].size);
+        assert!(storage.create_snapshot(4, cs.clone(), vec![]).is_err());
+        drop(storage);
+
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.first_index().unwrap(), 5);
+        assert_eq!(storage.last_index().unwrap(), 6);
+        assert_eq!(storage.term(4).unwrap(), 2);
+        assert!(storage.entries(4, 7, None).is_err());
+        assert_eq!(storage.entries(5, 7, None).unwrap(), vec![new_entry(5, 2), new_entry(6, 2)]);
+
+        let snapshot = storage.snapshot().unwrap();
+        assert_eq!(snapshot.get_metadata().index, 4);
+        assert_eq!(snapshot.get_metadata().get_conf_state(), &cs);
+        assert_eq!(snapshot.data, b"state machine".to_vec());
+    }
+}
@@ -22,4 +22,15 @@ mod peer_fsm;
 pub use crate::peer_fsm::*;
 mod peer_fsm_delegate;
 pub use crate::peer_fsm_delegate::*;
-
+mod raft_storage;
+pub use crate::raft_storage::*;
+mod disk_storage;
+pub use crate::disk_storage::*;
+mod state_machine;
+pub use crate::state_machine::*;
+mod compaction;
+pub use crate::compaction::*;
+mod config;
+pub use crate::config::*;
+mod apply;
+pub use crate::apply::*;
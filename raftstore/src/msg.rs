@@ -1,43 +1,147 @@
 use std::sync::mpsc::{SyncSender, Receiver};
 
-use raft::eraftpb::ConfChange;
+use raft::eraftpb::{ConfChange, Message};
 use std::sync::mpsc;
+use command::{Request, Response};
+use std::time::{Duration, Instant};
+use crate::apply::ApplyResult;
+
+// time a proposal may wait for its entry to be committed before it fails
+pub const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum ProposalError {
+    // the node is not the leader. leader_id is the leader known by the node
+    #[error("node is not the leader, leader is {leader_id}")]
+    NotLeader { leader_id: u64 },
+    // the entry of the proposal was overwritten by the entries of another leader
+    #[error("leadership lost")]
+    LeadershipLost,
+    // the proposal wasn't committed before its deadline
+    #[error("proposal timed out")]
+    Timeout,
+    // raft refused to append the proposal, e.g. during a leader transfer
+    #[error("proposal dropped")]
+    Dropped,
+    // the node stopped before answering the proposal
+    #[error("node is stopped")]
+    Stopped,
+    // the proposal is refused before being appended, e.g. a membership change without voters
+    #[error("invalid proposal: {0}")]
+    Invalid(String),
+    // the proposal couldn't be forwarded to the leader
+    #[error("cannot forward proposal to the leader: {0}")]
+    Forward(String),
+    // the entry was committed but the state machine couldn't apply it
+    #[error("cannot apply proposal: {0}")]
+    Apply(String),
+}
+
+// PeerMsg wakes up the poller of a node, which waits on a single mailbox
+#[derive(Debug)]
+pub enum PeerMsg {
+    // a Raft message sent by another node
+    Raft(Message),
+    // proposals were added to the proposal queue
+    Proposals,
+    // the apply worker applied committed entries
+    Applied(ApplyResult),
+    // the apply worker couldn't restore a snapshot and stopped, the node must stop too
+    ApplyFailed(String),
+    // stops the poller
+    Stop,
+}
+
+// ProposalResult is sent back to the proposer: the responses of an applied request, empty for other proposals
+pub type ProposalResult = Result<Vec<Response>, ProposalError>;
 
 #[derive(Clone, Debug)]
 pub struct Proposal {
-    pub normal: Option<(u16, String)>,
-    // key is an u16 integer, and value is a string.
+    pub normal: Option<Request>,
+    // request which is applied to the state machine once committed.
     pub conf_change: Option<ConfChange>,
     // conf change.
     pub transfer_leader: Option<u64>,
+    // read index request, answered once the local state machine applied the read index.
+    pub read_index: bool,
     // If it's proposed, it will be set to the index of the entry.
     pub proposed: u64,
-    pub propose_success: SyncSender<bool>,
+    // If it's proposed, it will be set to the term of the entry.
+    pub term: u64,
+    // the proposal fails with a timeout once the deadline is passed
+    pub deadline: Instant,
+    // set when the proposal was forwarded by a follower, so it isn't forwarded again
+    pub forwarded: bool,
+    pub propose_success: SyncSender<ProposalResult>,
 }
 
 impl Proposal {
-    pub fn conf_change(cc: &ConfChange) -> (Self, Receiver<bool>) {
+    pub fn conf_change(cc: &ConfChange) -> (Self, Receiver<ProposalResult>) {
         let (tx, rx) = mpsc::sync_channel(1);
         let proposal = Proposal {
             normal: None,
             conf_change: Some(cc.clone()),
             transfer_leader: None,
+            read_index: false,
             proposed: 0,
+            term: 0,
+            deadline: Instant::now() + PROPOSAL_TIMEOUT,
+            forwarded: false,
             propose_success: tx,
         };
         (proposal, rx)
     }
 
-    pub fn normal(key: u16, value: String) -> (Self, Receiver<bool>) {
+    pub fn normal(request: Request) -> (Self, Receiver<ProposalResult>) {
         let (tx, rx) = mpsc::sync_channel(1);
         let proposal = Proposal {
-            normal: Some((key, value)),
+            normal: Some(request),
             conf_change: None,
             transfer_leader: None,
+            read_index: false,
             proposed: 0,
+            term: 0,
+            deadline: Instant::now() + PROPOSAL_TIMEOUT,
+            forwarded: false,
+            propose_success: tx,
+        };
+        (proposal, rx)
+    }
+
+    pub fn transfer_leader(transferee: u64) -> (Self, Receiver<ProposalResult>) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let proposal = Proposal {
+            normal: None,
+            conf_change: None,
+            transfer_leader: Some(transferee),
+            read_index: false,
+            proposed: 0,
+            term: 0,
+            deadline: Instant::now() + PROPOSAL_TIMEOUT,
+            forwarded: false,
             propose_success: tx,
         };
         (proposal, rx)
     }
-}
 
+    pub fn read_index() -> (Self, Receiver<ProposalResult>) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let proposal = Proposal {
+            normal: None,
+            conf_change: None,
+            transfer_leader: None,
+            read_index: true,
+            proposed: 0,
+            term: 0,
+            deadline: Instant::now() + PROPOSAL_TIMEOUT,
+            forwarded: false,
+            propose_success: tx,
+        };
+        (proposal, rx)
+    }
+
+    // sends the error to the proposer, who may have given up waiting
+    pub fn fail(self, err: ProposalError) {
+        let _ = self.propose_success.send(Err(err));
+    }
+}
@@ -1,16 +1,25 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{self, Receiver, Sender};
 
-use raft::{prelude::*};
-use crate::msg::Proposal;
+use crate::msg::PeerMsg;
 
+// NetworkOutbound is the mailbox of the node: Raft messages from other nodes, wake ups for new proposals
+// and the stop request all arrive through it
 pub struct NetworkOutbound {
-    pub internal_message_receiver: Receiver<Message>,
+    pub internal_message_receiver: Receiver<PeerMsg>,
+    sender: Sender<PeerMsg>,
 }
 
 impl NetworkOutbound {
-    pub fn new(receiver: Receiver<Message>) -> Self {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
         NetworkOutbound {
             internal_message_receiver: receiver,
+            sender,
         }
     }
-}
\ No newline at end of file
+
+    // sender to the mailbox, e.g. for the transport which receives the messages of other nodes
+    pub fn sender(&self) -> Sender<PeerMsg> {
+        self.sender.clone()
+    }
+}
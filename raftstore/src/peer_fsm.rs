@@ -1,31 +1,88 @@
 use protobuf::Message as PbMessage;
-use raft::{prelude::*, StateRole};
+use raft::{prelude::*, StateRole, INVALID_ID};
 use crate::network_outbound::NetworkOutbound;
-use crate::msg::Proposal;
+use crate::msg::{Proposal, ProposalError};
+use std::time::Instant;
 use crate::peer_sender::PeerSender;
 use std::sync::{Arc, Mutex};
-use std::collections::VecDeque;
-use crate::proposal_queue::ProposalQueue;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{HashMap, VecDeque};
 use crate::network_inbound::NetworkInbound;
-use raft::storage::MemStorage;
+use crate::raft_storage::RaftStorage;
+use crate::apply::{ApplyResult, ApplyTask, ApplyWorker};
+use crate::compaction::LogMetrics;
+use crate::config::RaftConfig;
+use command::RequestBatch;
 
 pub trait Fsm {}
 
-pub struct PeerFsm<A: PeerSender<Message=Message>, B: PeerSender<Message=Proposal>>
+pub struct PeerFsm<A: PeerSender<Message=Message>, B: PeerSender<Message=Proposal>, S: RaftStorage>
 {
     pub id: u64,
-    pub raft_group: RawNode<MemStorage>,
+    pub raft_group: RawNode<S>,
     network_inbound: NetworkInbound<A, B>,
-    proposal_queue: ProposalQueue,
+    // proposals appended to the log and waiting for their entry to be committed, ordered by index
+    proposals: VecDeque<Proposal>,
+    // leader transfer in progress, completed once the transferee is the leader
+    transfer: Option<Proposal>,
+    // membership change whose joint configuration is committed, completed once the leader finalized it
+    membership_change: Option<Proposal>,
+    // read index requests waiting for their read state, by request context
+    pending_reads: HashMap<Vec<u8>, Proposal>,
+    // read index requests waiting for the state machine to apply their read index
+    reads: VecDeque<Proposal>,
+    read_seq: u64,
+    // writes waiting to be proposed together as a single entry
+    batch: Vec<Proposal>,
+    batch_data: RequestBatch,
+    max_batch_requests: usize,
+    max_batch_bytes: u64,
+    // applies the committed entries to the state machine
+    apply_worker: ApplyWorker,
+    // index of the last entry applied to the state machine, behind the applied index of raft while the worker is busy
+    applied_index: u64,
+    metrics: Arc<Mutex<LogMetrics>>,
+    // leader known by this node, shared with the RaftStore
+    shared_leader_id: Arc<AtomicU64>,
+    // term in which this node is the leader, None on followers
+    leader_term: Option<u64>,
 }
 
-impl<A, B> PeerFsm<A, B>
+impl<A, B, S> PeerFsm<A, B, S>
     where
         A: PeerSender<Message=Message>,
         B: PeerSender<Message=Proposal>,
+        S: RaftStorage,
 {
-    pub fn new(id: u64, raft_group: RawNode<MemStorage>, network_inbound: NetworkInbound<A, B>, proposal_queue: ProposalQueue) -> Self {
-        PeerFsm { id, network_inbound, proposal_queue, raft_group }
+    pub fn new(
+        id: u64,
+        raft_group: RawNode<S>,
+        network_inbound: NetworkInbound<A, B>,
+        apply_worker: ApplyWorker,
+        config: &RaftConfig,
+        metrics: Arc<Mutex<LogMetrics>>,
+        shared_leader_id: Arc<AtomicU64>,
+    ) -> Self {
+        PeerFsm {
+            id,
+            network_inbound,
+            proposals: VecDeque::new(),
+            transfer: None,
+            membership_change: None,
+            pending_reads: HashMap::new(),
+            reads: VecDeque::new(),
+            read_seq: 0,
+            batch: Vec::new(),
+            batch_data: RequestBatch::new(),
+            max_batch_requests: config.max_batch_requests,
+            max_batch_bytes: config.max_batch_bytes,
+            applied_index: raft_group.raft.raft_log.applied,
+            raft_group,
+            apply_worker,
+            metrics,
+            shared_leader_id,
+            leader_term: None,
+        }
     }
 
     pub fn tick(&mut self) {
@@ -36,11 +93,97 @@ impl<A, B> PeerFsm<A, B>
         self.raft_group.raft.state == StateRole::Leader
     }
 
+    // id of the leader known by this node, 0 during an election
+    pub fn leader_id(&self) -> u64 {
+        self.raft_group.raft.leader_id
+    }
+
+    // forwards the proposal to the leader, which answers the proposer directly.
+    // the proposal is given back when there is no connection to the leader
+    pub fn forward_proposal(&self, leader_id: u64, mut proposal: Proposal) -> Result<(), Proposal> {
+        proposal.forwarded = true;
+        match self.network_inbound.get_proposal_sender(leader_id) {
+            Some(sender) => sender.send(proposal).map_err(|err| err.0),
+            None => Err(proposal),
+        }
+    }
+
+    // fails the proposals waiting for an entry which are passed their deadline
+    pub fn expire_proposals(&mut self, now: Instant) {
+        let (expired, pending) = self.proposals.drain(..).partition(|p| p.deadline <= now);
+        self.proposals = pending;
+        for proposal in expired {
+            proposal.fail(ProposalError::Timeout);
+        }
+        if self.transfer.as_ref().map_or(false, |p| p.deadline <= now) {
+            self.transfer.take().unwrap().fail(ProposalError::Timeout);
+        }
+        if self.membership_change.as_ref().map_or(false, |p| p.deadline <= now) {
+            self.membership_change.take().unwrap().fail(ProposalError::Timeout);
+        }
+
+        // raft forgets the read requests on a leader change, they are only answered by their deadline
+        let expired: Vec<_> = self.pending_reads.iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(ctx, _)| ctx.clone())
+            .collect();
+        for ctx in expired {
+            self.pending_reads.remove(&ctx).unwrap().fail(ProposalError::Timeout);
+        }
+        let (expired, pending) = self.reads.drain(..).partition(|p| p.deadline <= now);
+        self.reads = pending;
+        for proposal in expired {
+            proposal.fail(ProposalError::Timeout);
+        }
+    }
+
+    // completes the leader transfer once the leadership moved, or fails it when raft gave up
+    fn check_transfer(&mut self) {
+        let transferee = match self.transfer {
+            Some(ref p) => p.transfer_leader.unwrap(),
+            None => return,
+        };
+
+        let raft = &self.raft_group.raft;
+        if raft.leader_id == transferee {
+            // the proposer may have given up waiting
+            let _ = self.transfer.take().unwrap().propose_success.send(Ok(vec![]));
+        } else if raft.state != StateRole::Leader {
+            // the transferee is campaigning until this node hears from the new leader
+            if raft.leader_id != INVALID_ID {
+                let leader_id = raft.leader_id;
+                self.transfer.take().unwrap().fail(ProposalError::NotLeader { leader_id });
+            }
+        } else if raft.lead_transferee.is_none() {
+            // raft aborts the transfer when the transferee isn't elected within an election timeout
+            self.transfer.take().unwrap().fail(ProposalError::Timeout);
+        }
+    }
+
+    // once the node isn't the leader anymore, the proposals and the reads waiting for it fail right away, so the
+    // proposers can retry on the new leader. A write may still be committed by the new leader.
+    fn check_step_down(&mut self) {
+        let raft = &self.raft_group.raft;
+        let leader_term = if raft.state == StateRole::Leader { Some(raft.term) } else { None };
+        if self.leader_term.is_some() && self.leader_term != leader_term {
+            let leader_id = raft.leader_id;
+            let pending_reads = self.pending_reads.drain().map(|(_, p)| p);
+            for proposal in self.proposals.drain(..).chain(pending_reads).chain(self.reads.drain(..)) {
+                proposal.fail(ProposalError::NotLeader { leader_id });
+            }
+        }
+        self.leader_term = leader_term;
+    }
+
     pub fn on_peer_message(&mut self, msg: Message) {
         self.raft_group.step(msg);
     }
 
     pub fn on_ready(&mut self) {
+        self.check_transfer();
+        self.check_step_down();
+        self.shared_leader_id.store(self.raft_group.raft.leader_id, Ordering::SeqCst);
+
         let mut raft_group = &mut self.raft_group;
         if !raft_group.has_ready() {
             return;
@@ -49,19 +192,29 @@ impl<A, B> PeerFsm<A, B>
         // Get the `Ready` with `RawNode::ready` interface.
         let mut ready = raft_group.ready();
 
-        let store = raft_group.raft.raft_log.store.clone();
+        // Apply the snapshot. It's necessary because in `RawNode::advance` we stabilize the snapshot.
+        // It comes before the entries of the same ready, which follow the snapshot.
+        if *ready.snapshot() != Snapshot::default() {
+            let s = ready.snapshot().clone();
+            if let Err(e) = raft_group.mut_store().apply_snapshot(s.clone()) {
+                eprintln!("apply snapshot fail: {:?}, need to retry or panic", e);
+                return;
+            }
+            // the worker restores the state machine once the entries scheduled before are applied
+            self.apply_worker.schedule(ApplyTask::Restore(s));
+        }
 
         // Persistent raft logs. It's necessary because in `RawNode::advance` we stabilize raft logs to the latest position.
-        if let Err(e) = store.wl().append(ready.entries()) {
+        // The storage syncs every write, so nothing is sent out before the entries are durable.
+        if let Err(e) = raft_group.mut_store().append(ready.entries()) {
             eprintln!("persist raft log fail: {:?}, need to retry or panic", e);
             return;
         }
 
-        // Apply the snapshot. It's necessary because in `RawNode::advance` we stabilize the snapshot.
-        if *ready.snapshot() != Snapshot::default() {
-            let s = ready.snapshot().clone();
-            if let Err(e) = store.wl().apply_snapshot(s) {
-                eprintln!("apply snapshot fail: {:?}, need to retry or panic", e);
+        // Persist the term, the vote and the commit index before any message announces them.
+        if let Some(hs) = ready.hs() {
+            if let Err(e) = raft_group.mut_store().set_hard_state(hs.clone()) {
+                eprintln!("persist hard state fail: {:?}, need to retry or panic", e);
                 return;
             }
         }
@@ -81,101 +234,379 @@ impl<A, B> PeerFsm<A, B>
             // }
         }
 
-        // Apply all committed proposals.
+        // Apply all committed proposals. Conf changes are applied here, as raft needs them right away,
+        // normal entries are handed to the apply worker with their proposals.
         if let Some(committed_entries) = ready.committed_entries.take() {
-            for entry in &committed_entries {
-                println!("entry: {:?}", entry);
-                // When the peer becomes Leader it will send an empty entry.
+            let mut entries = Vec::with_capacity(committed_entries.len());
+            for entry in committed_entries {
+                // Tell the clients if their proposals succeeded or not. This is done by every node,
+                // not only the leader: a former leader fails the proposals which were overwritten.
+                let mut proposals = take_proposals(&mut self.proposals, &entry);
+                let mut result = Ok(vec![]);
+                let mut change_type = None;
                 if entry.data.is_empty() {
-                    continue;
-                }
-
-                if let EntryType::EntryConfChange = entry.get_entry_type() {
+                    // When the peer becomes Leader it will send an empty entry.
+                    // Raft also replaces a conf change by an empty entry while another one is pending.
+                    result = Err(ProposalError::Dropped);
+                } else if let EntryType::EntryConfChange = entry.get_entry_type() {
                     println!("node: {} content:{:?}", self.id, entry);
                     // For conf change messages, make them effective.
                     let mut cc = ConfChange::default();
                     cc.merge_from_bytes(&entry.data).unwrap();
                     let node_id = cc.node_id;
-                    match cc.get_change_type() {
-                        ConfChangeType::AddNode => raft_group.raft.add_node(node_id).unwrap(),
-                        ConfChangeType::RemoveNode => raft_group.raft.remove_node(node_id).unwrap(),
-                        ConfChangeType::AddLearnerNode => raft_group.raft.add_learner(node_id).unwrap(),
-                        ConfChangeType::BeginMembershipChange
-                        | ConfChangeType::FinalizeMembershipChange => unimplemented!(),
+                    change_type = Some(cc.get_change_type());
+                    let applied = match cc.get_change_type() {
+                        ConfChangeType::AddNode => raft_group.raft.add_node(node_id),
+                        ConfChangeType::RemoveNode => raft_group.raft.remove_node(node_id),
+                        ConfChangeType::AddLearnerNode => raft_group.raft.add_learner(node_id),
+                        // the leader appends the finalize entry by itself once the begin entry is applied
+                        ConfChangeType::BeginMembershipChange => raft_group.raft.begin_membership_change(&cc),
+                        ConfChangeType::FinalizeMembershipChange => raft_group.raft.finalize_membership_change(&cc),
+                    };
+                    match applied {
+                        Ok(_) => {
+                            // in a joint configuration, this is the configuration before the change,
+                            // the one after it is kept aside until the change is finalized
+                            let cs = ConfState::from(raft_group.raft.prs().configuration().clone());
+                            let pending = raft_group.raft.pending_membership_change().as_ref()
+                                .map(|cc| (cc.get_configuration().clone(), cc.start_index));
+                            if let Err(e) = raft_group.mut_store().set_conf_state(cs, pending) {
+                                eprintln!("persist conf state fail: {:?}, need to retry or panic", e);
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("node {}: cannot apply conf change {}: {}", self.id, entry.index, e);
+                            result = Err(ProposalError::Apply(e.to_string()));
+                        }
                     }
-                    let cs = ConfState::from(raft_group.raft.prs().configuration().clone());
-                    store.wl().set_conf_state(cs, None);
                 } else {
-                    // For normal proposals, extract the key-value pair and then
-                    // insert them into the kv engine.
-
-                    // TODO implement to add to DB
-                    // let data = str::from_utf8(&entry.data).unwrap();
-                    // let reg = Regex::new("put ([0-9]+) (.+)").unwrap();
-                    // if let Some(caps) = reg.captures(&data) {
-                    //     // TODO
-                    //     self.fsm_callback.on_committed_entry(data.as_ref());
-                    //     // kv_pairs.insert(caps[1].parse().unwrap(), caps[2].to_string());
-                    // }
+                    // the worker answers the proposals of the batch once it's applied
+                    entries.push((entry, proposals));
+                    continue;
                 }
 
-                // TODO work here
-                if raft_group.raft.state == StateRole::Leader {
-                    // The leader should response to the clients, tell them if their proposals succeeded or not.
-                    println!("proposal size: {}", self.proposal_queue.get_ref().lock().unwrap().len());
-                    if let Some(proposal) = self.proposal_queue.remove_proposal() {
-                        println!("leader: pullout proposal. {:?}", proposal);
-                        proposal.propose_success.send(true).unwrap();
+                // other entries hold a single proposal
+                let proposal = proposals.pop();
+                if change_type == Some(ConfChangeType::BeginMembershipChange) && result.is_ok() {
+                    // the change is done when the joint configuration is left
+                    self.membership_change = proposal;
+                } else if change_type == Some(ConfChangeType::FinalizeMembershipChange) {
+                    if let Some(p) = self.membership_change.take() {
+                        let _ = p.propose_success.send(result);
                     }
+                } else if let Some(p) = proposal {
+                    // the proposer may have given up waiting
+                    let _ = p.propose_success.send(result);
                 }
+                entries.push((entry, vec![]));
             }
 
-            if let Some(last_committed) = committed_entries.last() {
-                let mut s = store.wl();
-                s.mut_hard_state().commit = last_committed.index;
-                s.mut_hard_state().term = last_committed.term;
+            if !entries.is_empty() {
+                let conf_state = ConfState::from(raft_group.raft.prs().configuration().clone());
+                self.apply_worker.schedule(ApplyTask::Apply { entries, conf_state });
+            }
+        }
+
+        // The read index of a read request is known once the leader confirmed its leadership.
+        for rs in ready.read_states.drain(..) {
+            if let Some(mut p) = self.pending_reads.remove(&rs.request_ctx) {
+                p.proposed = rs.index;
+                self.reads.push_back(p);
             }
         }
 
         // Call `RawNode::advance` interface to update position flags in the raft.
         raft_group.advance(ready);
+        self.complete_reads();
     }
 
-    pub fn on_proposal_normal(&mut self, proposal: &mut Proposal) {
-        if let Some((ref key, ref value)) = proposal.normal {
-            let last_index1 = self.raft_group.raft.raft_log.last_index() + 1;
-            let data = format!("put {} {}", key, value).into_bytes();
-            println!("proposal_message {:?}", value);
-            let result = self.raft_group.propose(vec![], data);
+    // answers the reads whose read index is applied, the reader then queries its local state machine
+    fn complete_reads(&mut self) {
+        let applied = self.applied_index;
+        let (done, waiting) = self.reads.drain(..).partition(|p| p.proposed <= applied);
+        self.reads = waiting;
+        for p in done {
+            // the reader may have given up waiting
+            let _ = p.propose_success.send(Ok(vec![]));
+        }
+    }
 
-            let last_index2 = self.raft_group.raft.raft_log.last_index() + 1;
-            if last_index2 == last_index1 {
-                println!("propose fail");
-                // Propose failed, don't forget to respond to the client.
-                proposal.propose_success.send(false).unwrap();
-            } else {
-                println!("normal propose: propose success -> {}", last_index1);
+    // the apply worker applied the entries up to result.applied_index.
+    // When it took a snapshot of the state machine, the log behind it is discarded
+    pub fn on_applied(&mut self, result: ApplyResult) {
+        self.applied_index = result.applied_index;
+        self.complete_reads();
+
+        let (cs, data) = match result.snapshot {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let index = result.applied_index;
+        let result = self.log_size().and_then(|before| {
+            // a snapshot received from the leader may be ahead of the state machine
+            if index >= self.raft_group.get_store().first_index()? {
+                self.raft_group.mut_store().create_snapshot(index, cs, data)?;
+            }
+            Ok((before, self.log_size()?))
+        });
+        let ((entries_before, bytes_before), (entries_after, bytes_after)) = match result {
+            Ok(sizes) => sizes,
+            Err(e) => {
+                eprintln!("node {}: compact raft log fail: {:?}", self.id, e);
+                return;
+            }
+        };
+
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.compactions += 1;
+        metrics.snapshot_index = index;
+        metrics.entries_before = entries_before;
+        metrics.bytes_before = bytes_before;
+        metrics.entries_after = entries_after;
+        metrics.bytes_after = bytes_after;
+    }
+
+    // number of entries and bytes of the log
+    fn log_size(&self) -> raft::Result<(u64, u64)> {
+        let store = self.raft_group.get_store();
+        let entries = store.last_index()? + 1 - store.first_index()?;
+        Ok((entries, store.log_bytes()?))
+    }
+
+    // asks the leader for the read index of a linearizable read, followers forward it to the leader.
+    pub fn on_proposal_read_index(&mut self, proposal: Proposal) {
+        self.read_seq += 1;
+        let ctx = self.read_seq.to_be_bytes().to_vec();
+        self.raft_group.read_index(ctx.clone());
+        self.pending_reads.insert(ctx, proposal);
+    }
+
+    // adds the request to the batch, which is proposed once it's full or no proposal is left in the queue
+    pub fn on_proposal_normal(&mut self, proposal: Proposal) {
+        if let Some(ref request) = proposal.normal {
+            self.batch_data.push(request);
+            self.batch.push(proposal);
+            if self.batch.len() >= self.max_batch_requests || self.batch_data.size() as u64 >= self.max_batch_bytes {
+                self.propose_batch();
+            }
+        }
+    }
+
+    // proposes the batched requests as a single entry, without waiting for the previous entries to commit
+    pub fn propose_batch(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let data = std::mem::replace(&mut self.batch_data, RequestBatch::new()).encode();
+        let batch = std::mem::take(&mut self.batch);
+
+        let last_index1 = self.raft_group.raft.raft_log.last_index() + 1;
+        let result = self.raft_group.propose(vec![], data);
+
+        let last_index2 = self.raft_group.raft.raft_log.last_index() + 1;
+        if last_index2 == last_index1 {
+            // Propose failed, don't forget to respond to the clients.
+            for proposal in batch {
+                proposal.fail(ProposalError::Dropped);
+            }
+        } else {
+            let term = self.raft_group.raft.term;
+            for mut proposal in batch {
                 proposal.proposed = last_index1;
+                proposal.term = term;
+                self.proposals.push_back(proposal);
             }
         }
     }
 
-    pub fn on_proposal_cfg_change(&mut self, proposal: &mut Proposal) {
+    pub fn on_proposal_cfg_change(&mut self, mut proposal: Proposal) {
         if let Some(ref cc) = proposal.conf_change {
             let last_index1 = self.raft_group.raft.raft_log.last_index() + 1;
+            let mut cc = cc.clone();
+
+            match cc.get_change_type() {
+                ConfChangeType::BeginMembershipChange => {
+                    let cs = cc.get_configuration();
+                    if cs.nodes.is_empty() || cs.nodes.iter().any(|id| cs.learners.contains(id)) {
+                        proposal.fail(ProposalError::Invalid(format!("invalid configuration: {:?}", cs)));
+                        return;
+                    }
+                    // the joint configuration starts at the index of the begin entry
+                    cc.start_index = last_index1;
+                }
+                ConfChangeType::FinalizeMembershipChange => {
+                    proposal.fail(ProposalError::Invalid(String::from("membership changes are finalized by the leader")));
+                    return;
+                }
+                _ => {}
+            }
 
             println!("proposal_configuration_change {:?}", cc);
-            let res = self.raft_group.propose_conf_change(vec![], cc.clone());
+            let res = self.raft_group.propose_conf_change(vec![], cc);
 
             let last_index2 = self.raft_group.raft.raft_log.last_index() + 1;
             if last_index2 == last_index1 {
                 println!("propose fail");
                 // Propose failed, don't forget to respond to the client.
-                proposal.propose_success.send(false).unwrap();
+                proposal.fail(ProposalError::Dropped);
             } else {
                 println!("cfg change propose: propose success -> {}", last_index1);
                 proposal.proposed = last_index1;
+                proposal.term = self.raft_group.raft.term;
+                self.proposals.push_back(proposal);
             }
         }
     }
-}
\ No newline at end of file
+
+    pub fn on_proposal_transfer_leader(&mut self, proposal: Proposal) {
+        let transferee = proposal.transfer_leader.unwrap();
+        if transferee == self.id {
+            let _ = proposal.propose_success.send(Ok(vec![]));
+            return;
+        }
+        // a new transfer replaces the one in progress
+        if let Some(previous) = self.transfer.take() {
+            previous.fail(ProposalError::Dropped);
+        }
+
+        self.raft_group.transfer_leader(transferee);
+        if self.raft_group.raft.lead_transferee != Some(transferee) {
+            // raft ignores a transfer to a learner or to a node outside of the cluster
+            proposal.fail(ProposalError::Dropped);
+            return;
+        }
+        self.transfer = Some(proposal);
+    }
+}
+
+// takes the proposal appended at the same index and term as the committed entry.
+// The other ones up to this index were overwritten by the entries of another leader and fail.
+fn take_proposals(proposals: &mut VecDeque<Proposal>, entry: &Entry) -> Vec<Proposal> {
+    let mut taken = Vec::new();
+    while proposals.front().map_or(false, |p| p.proposed <= entry.index) {
+        let proposal = proposals.pop_front().unwrap();
+        if proposal.proposed == entry.index && proposal.term == entry.term {
+            taken.push(proposal);
+        } else {
+            proposal.fail(ProposalError::LeadershipLost);
+        }
+    }
+    taken
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{Parameters, Request, Statement};
+    use std::sync::mpsc::{self, Receiver};
+    use raft::storage::MemStorage;
+    use crate::msg::ProposalResult;
+    use crate::peer_sender::ChannelSender;
+    use crate::state_machine::StateMachine;
+
+    struct NoopStateMachine;
+
+    impl StateMachine for NoopStateMachine {
+        fn apply(&mut self, _req: &Request) -> Result<Vec<command::Response>, String> {
+            Ok(vec![])
+        }
+
+        fn snapshot(&self) -> Result<Vec<u8>, String> {
+            Ok(vec![])
+        }
+
+        fn restore(&mut self, _data: &[u8]) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    // a single node cluster, node 1 is elected
+    fn leader() -> PeerFsm<ChannelSender<Message>, ChannelSender<Proposal>, MemStorage> {
+        let config = RaftConfig::default();
+        let storage = MemStorage::new();
+        let mut s = Snapshot::default();
+        s.mut_metadata().index = 1;
+        s.mut_metadata().term = 1;
+        s.mut_metadata().mut_conf_state().nodes = vec![1];
+        storage.wl().apply_snapshot(s).unwrap();
+        let mut raft_group = RawNode::new(&config.raft_config(1, 0), storage).unwrap();
+        raft_group.campaign().unwrap();
+
+        let (mailbox, _) = mpsc::channel();
+        let apply_worker = ApplyWorker::start(1, NoopStateMachine, 0, config.compaction.clone(), mailbox);
+        let mut fsm = PeerFsm::new(
+            1, raft_group, NetworkInbound::new(), apply_worker, &config, Default::default(), Default::default());
+        fsm.on_ready();
+        assert!(fsm.is_leader());
+        fsm
+    }
+
+    fn proposed(index: u64, term: u64) -> (Proposal, Receiver<ProposalResult>) {
+        let request = Request {
+            transaction: false,
+            statements: Box::new([Statement { sql: String::from("DELETE FROM foo"), parameters: Parameters::default() }]),
+        };
+        let (mut proposal, rx) = Proposal::normal(request);
+        proposal.proposed = index;
+        proposal.term = term;
+        (proposal, rx)
+    }
+
+    fn entry(index: u64, term: u64) -> Entry {
+        let mut entry = Entry::default();
+        entry.index = index;
+        entry.term = term;
+        entry
+    }
+
+    #[test]
+    fn test_take_proposals() {
+        let mut proposals = VecDeque::new();
+        let (p1, _rx1) = proposed(2, 1);
+        let (batched, _rx) = proposed(2, 1);
+        let (p2, rx2) = proposed(3, 1);
+        let (p3, rx3) = proposed(4, 1);
+        proposals.extend(vec![p1, batched, p2, p3]);
+
+        // a batch proposed by this node
+        assert_eq!(take_proposals(&mut proposals, &entry(2, 1)).len(), 2);
+        assert!(rx2.try_recv().is_err());
+        assert_eq!(proposals.len(), 2);
+
+        // a new leader overwrote index 3, and its entry at index 5 matches nothing either
+        assert!(take_proposals(&mut proposals, &entry(3, 2)).is_empty());
+        assert_eq!(rx2.try_recv().unwrap().unwrap_err(), ProposalError::LeadershipLost);
+        assert!(take_proposals(&mut proposals, &entry(5, 2)).is_empty());
+        assert_eq!(rx3.try_recv().unwrap().unwrap_err(), ProposalError::LeadershipLost);
+        assert!(proposals.is_empty());
+    }
+
+    #[test]
+    fn test_step_down() {
+        let mut fsm = leader();
+        let (proposal, rx) = proposed(0, 0);
+        fsm.on_proposal_normal(proposal);
+        fsm.propose_batch();
+        assert_eq!(fsm.proposals.len(), 1);
+        let (mut read, read_rx) = Proposal::read_index();
+        read.proposed = 100;
+        fsm.reads.push_back(read);
+        fsm.on_ready();
+        assert!(rx.try_recv().is_err());
+
+        // node 2 is elected in a later term, the node only hears from it once
+        let mut msg = Message::default();
+        msg.set_msg_type(MessageType::MsgHeartbeat);
+        msg.from = 2;
+        msg.to = 1;
+        msg.term = fsm.raft_group.raft.term + 1;
+        fsm.on_peer_message(msg);
+        fsm.on_ready();
+        assert!(!fsm.is_leader());
+        assert_eq!(rx.try_recv().unwrap().unwrap_err(), ProposalError::NotLeader { leader_id: 2 });
+        assert_eq!(read_rx.try_recv().unwrap().unwrap_err(), ProposalError::NotLeader { leader_id: 2 });
+        assert!(fsm.proposals.is_empty());
+        assert!(fsm.reads.is_empty());
+    }
+
+}
@@ -1,22 +1,27 @@
-use raft::{prelude::*, StateRole};
+use raft::{prelude::*, StateRole, INVALID_ID};
+use std::time::Instant;
 
 use crate::peer_fsm::PeerFsm;
 use crate::peer_sender::PeerSender;
-use crate::msg::Proposal;
+use crate::msg::{Proposal, ProposalError};
 use crate::proposal_queue::ProposalQueue;
+use crate::raft_storage::RaftStorage;
+use crate::apply::ApplyResult;
 
-pub struct PeerFsmDelegate<A, B> where
+pub struct PeerFsmDelegate<A, B, S> where
     A: PeerSender<Message=Message>,
-    B: PeerSender<Message=Proposal>
+    B: PeerSender<Message=Proposal>,
+    S: RaftStorage,
 {
-    fsm: PeerFsm<A, B>
+    fsm: PeerFsm<A, B, S>
 }
 
-impl<A, B> PeerFsmDelegate<A, B> where
+impl<A, B, S> PeerFsmDelegate<A, B, S> where
     A: PeerSender<Message=Message>,
-    B: PeerSender<Message=Proposal>
+    B: PeerSender<Message=Proposal>,
+    S: RaftStorage,
 {
-    pub fn new(fsm: PeerFsm<A, B>) -> PeerFsmDelegate<A, B> {
+    pub fn new(fsm: PeerFsm<A, B, S>) -> PeerFsmDelegate<A, B, S> {
         PeerFsmDelegate { fsm }
     }
 
@@ -33,22 +38,58 @@ impl<A, B> PeerFsmDelegate<A, B> where
         self.fsm.on_ready();
     }
 
+    pub fn on_applied(&mut self, result: ApplyResult) {
+        self.fsm.on_applied(result);
+    }
+
+    // fails the queued and the proposed proposals which are passed their deadline
+    pub fn expire_proposals(&mut self, mut proposal_queue: ProposalQueue) {
+        let now = Instant::now();
+        for p in proposal_queue.remove_expired(now) {
+            p.fail(ProposalError::Timeout);
+        }
+        self.fsm.expire_proposals(now);
+    }
+
     pub fn handle_proposals(&mut self, mut proposal_queue: ProposalQueue) {
         if !self.fsm.is_leader() {
+            // during an election the proposals wait for a leader, until their deadline
+            let leader_id = self.fsm.leader_id();
+            if leader_id == INVALID_ID {
+                return;
+            }
+            // writes are forwarded once to the leader, the other proposals must be sent to the leader by the client.
+            // Raft itself asks the leader for the read index of reads
+            while let Some(p) = proposal_queue.remove_proposal() {
+                if p.read_index {
+                    self.fsm.on_proposal_read_index(p);
+                } else if p.normal.is_none() || p.forwarded {
+                    p.fail(ProposalError::NotLeader { leader_id });
+                } else if let Err(p) = self.fsm.forward_proposal(leader_id, p) {
+                    p.fail(ProposalError::NotLeader { leader_id });
+                }
+            }
             return;
         }
 
-        let mut fsm = &mut self.fsm;
+        // proposed ones are kept by the fsm until their entry is committed.
+        // Writes queued together are batched into a single entry
+        while let Some(p) = proposal_queue.remove_proposal() {
+            if p.normal.is_some() {
+                self.fsm.on_proposal_normal(p);
+                continue;
+            }
 
-        for p in proposal_queue.get_ref().lock().unwrap().iter_mut().skip_while(|p| p.proposed > 0) {
-            if let Some((ref key, ref value)) = p.normal {
-                fsm.on_proposal_normal(p);
-            } else if let Some(ref cc) = p.conf_change {
-                fsm.on_proposal_cfg_change(p);
-            } else if let Some(_transferee) = p.transfer_leader {
-                // TODO: implement transfer leader.
-                unimplemented!();
+            // the batch is proposed first, so the proposals keep their order
+            self.fsm.propose_batch();
+            if p.conf_change.is_some() {
+                self.fsm.on_proposal_cfg_change(p);
+            } else if p.transfer_leader.is_some() {
+                self.fsm.on_proposal_transfer_leader(p);
+            } else if p.read_index {
+                self.fsm.on_proposal_read_index(p);
             }
         }
+        self.fsm.propose_batch();
     }
 }
\ No newline at end of file
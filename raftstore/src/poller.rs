@@ -1,38 +1,39 @@
 use crate::ticker;
-use std::time::Duration;
 use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError, Sender, SyncSender};
 use crate::peer_fsm::PeerFsm;
 use crate::network_outbound::NetworkOutbound;
 
 use raft::{prelude::*};
 use std::sync::{Mutex, Arc, mpsc};
-use crate::msg::Proposal;
+use std::sync::atomic::AtomicU64;
+use crate::msg::{PeerMsg, Proposal};
 use crate::peer_sender::PeerSender;
 use std::collections::VecDeque;
 use crate::raft_store::RaftStore;
-use raft::storage::MemStorage;
-use std::thread;
+use crate::raft_storage::RaftStorage;
+use crate::state_machine::StateMachine;
+use std::thread::{self, JoinHandle};
 use crate::proposal_queue::ProposalQueue;
 use crate::network_inbound::NetworkInbound;
+use crate::compaction::LogMetrics;
+use crate::config::RaftConfig;
+use crate::apply::ApplyWorker;
 use crate::peer_fsm_delegate::PeerFsmDelegate;
 
-pub const RAFT_TIMEOUT: Duration = Duration::from_millis(100);
-
-fn default_config() -> Config {
-    Config {
-        election_tick: 10,
-        heartbeat_tick: 3,
-        ..Default::default()
-    }
-}
-
 pub struct Poller {
-    stop_snd: SyncSender<()>
+    mailbox: Sender<PeerMsg>,
+    handle: JoinHandle<()>,
 }
 
 impl Poller {
-    pub fn start<A, B>(
+    pub fn start<A, B, S, M>(
         id: u64,
+        storage: S,
+        state_machine: M,
+        applied: u64,
+        config: &RaftConfig,
+        metrics: Arc<Mutex<LogMetrics>>,
+        leader_id: Arc<AtomicU64>,
         proposal_queue: ProposalQueue,
         network_outbound: NetworkInbound<A, B>,
         network_inbound: NetworkOutbound,
@@ -41,51 +42,73 @@ impl Poller {
         where
             A: PeerSender<Message=Message> + Send + 'static,
             B: PeerSender<Message=Proposal> + Send + 'static,
+            S: RaftStorage + Send + 'static,
+            M: StateMachine + Send + 'static,
     {
-        let (stop_snd, stop_rcv) = mpsc::sync_channel(0);
-        let mut raft_ticker = ticker::Ticker::new(RAFT_TIMEOUT);
-        let mut timeout = RAFT_TIMEOUT;
+        let mailbox = network_inbound.sender();
+        let mut raft_ticker = ticker::Ticker::new(config.tick_interval());
+        let mut timeout = config.tick_interval();
 
         // create peer
-        let mut cfg = default_config();
-        cfg.id = id;
-        let storage = MemStorage::new();
-        if initialize {
+        let cfg = config.raft_config(id, applied);
+        let mut storage = storage;
+        // bootstrap only an empty log: a reopened storage already knows the cluster
+        if initialize && storage.last_index().unwrap() == 0 {
             let mut s = Snapshot::default();
             s.mut_metadata().index = 1;
             s.mut_metadata().term = 1;
             s.mut_metadata().mut_conf_state().nodes = vec![1];
-            storage.wl().apply_snapshot(s).unwrap();
+            storage.apply_snapshot(s).unwrap();
         }
 
         let raft_group = RawNode::new(&cfg, storage).unwrap();
-        let mut fsm = PeerFsm::new(id, raft_group, network_outbound, proposal_queue.clone());
+        // the worker reports the applied entries through the mailbox
+        let applied = raft_group.raft.raft_log.applied;
+        let apply_worker = ApplyWorker::start(id, state_machine, applied, config.compaction.clone(), mailbox.clone());
+        let mut fsm = PeerFsm::new(id, raft_group, network_outbound, apply_worker, config, metrics, leader_id);
         let mut fsm_delegate = PeerFsmDelegate::new(fsm);
 
-        let pq = proposal_queue.clone();
-        thread::spawn(move || {
-            loop {
-                match stop_rcv.try_recv() {
-                    Ok(_) | Err(TryRecvError::Disconnected) => {
-                        println!("Terminating node {}", id);
-                        break;
-                    }
-                    _ => {}
-                }
-
-                match network_inbound.internal_message_receiver.recv_timeout(timeout) {
-                    Err(RecvTimeoutError::Timeout) => {}
+        // the poller sleeps until a message, a proposal or the next tick
+        let mut pq = proposal_queue.clone();
+        pq.set_waker(mailbox.clone());
+        let handle = thread::spawn(move || {
+            'poll: loop {
+                let mut next = match network_inbound.internal_message_receiver.recv_timeout(timeout) {
+                    Ok(msg) => Some(msg),
+                    Err(RecvTimeoutError::Timeout) => None,
                     Err(RecvTimeoutError::Disconnected) => {
                         // TODO: cleanup all resource here
                         break;
                     }
-                    Ok(msg) => {
-                        fsm_delegate.handle_raft_msg(msg);
+                };
+
+                // everything already in the mailbox is handled before a single ready
+                while let Some(msg) = next {
+                    match msg {
+                        PeerMsg::Raft(msg) => {
+                            fsm_delegate.handle_raft_msg(msg);
+                        }
+                        // the proposal queue is looked at below
+                        PeerMsg::Proposals => {}
+                        PeerMsg::Applied(result) => {
+                            fsm_delegate.on_applied(result);
+                        }
+                        // the state machine is behind a snapshot it never installed, it can't catch up
+                        PeerMsg::ApplyFailed(err) => {
+                            eprintln!("{}, stopping node {}", err, id);
+                            break 'poll;
+                        }
+                        PeerMsg::Stop => {
+                            println!("Terminating node {}", id);
+                            break 'poll;
+                        }
                     }
+                    next = network_inbound.internal_message_receiver.try_recv().ok();
                 }
 
                 timeout = raft_ticker.tick(|| {
                     fsm_delegate.tick();
+                    fsm_delegate.expire_proposals(pq.clone());
                 });
 
                 fsm_delegate.handle_proposals(pq.clone());
@@ -94,10 +117,15 @@ impl Poller {
         });
 
 
-        Poller { stop_snd: stop_snd }
+        Poller { mailbox, handle }
     }
 
     pub fn stop(&self) {
-        self.stop_snd.send(());
+        let _ = self.mailbox.send(PeerMsg::Stop);
+    }
+
+    // waits for the node to stop once stop was called. Its storage and state machine can then be opened again
+    pub fn join(self) {
+        let _ = self.handle.join();
     }
 }
@@ -1,26 +1,92 @@
 use std::sync::{Mutex, Arc};
 use std::collections::VecDeque;
-use crate::msg::Proposal;
+use std::time::Instant;
+use std::sync::mpsc::Sender;
+use crate::msg::{PeerMsg, Proposal};
 
 #[derive(Clone)]
 pub struct ProposalQueue {
     proposals: Arc<Mutex<VecDeque::<Proposal>>>,
+    // mailbox of the poller, woken up by every new proposal
+    waker: Arc<Mutex<Option<Sender<PeerMsg>>>>,
 }
 
 impl ProposalQueue {
     pub fn new() -> Self {
-        ProposalQueue { proposals: Arc::new(Mutex::new(Default::default())) }
+        ProposalQueue { proposals: Arc::new(Mutex::new(Default::default())), waker: Default::default() }
+    }
+
+    pub fn set_waker(&mut self, waker: Sender<PeerMsg>) {
+        *self.waker.lock().unwrap() = Some(waker);
+    }
+
+    // the poller then only looks at the queue on its next tick or message, as it did before proposals woke it up.
+    // Only meant for the baseline of the commit latency benchmark, it isn't part of the API
+    #[doc(hidden)]
+    pub fn remove_waker(&mut self) {
+        *self.waker.lock().unwrap() = None;
     }
 
     pub fn add_proposal(&mut self, proposal: Proposal) {
         self.proposals.lock().unwrap().push_back(proposal);
+        if let Some(waker) = self.waker.lock().unwrap().as_ref() {
+            // the poller may be stopped already
+            let _ = waker.send(PeerMsg::Proposals);
+        }
     }
 
     pub fn remove_proposal(&mut self) -> Option<Proposal> {
         self.proposals.lock().unwrap().pop_front()
     }
 
+    // removes the proposals whose deadline is passed
+    pub fn remove_expired(&mut self, now: Instant) -> Vec<Proposal> {
+        let mut proposals = self.proposals.lock().unwrap();
+        let (expired, pending): (VecDeque<_>, _) = proposals.drain(..).partition(|p| p.deadline <= now);
+        *proposals = pending;
+        expired.into()
+    }
+
     pub fn get_ref(&mut self) -> Arc<Mutex<VecDeque<Proposal>>> {
         self.proposals.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::ProposalError;
+    use raft::prelude::ConfChange;
+
+    #[test]
+    fn test_remove_expired() {
+        let mut queue = ProposalQueue::new();
+        let (mut expired, rx1) = Proposal::conf_change(&ConfChange::default());
+        expired.deadline = Instant::now();
+        let (pending, rx2) = Proposal::conf_change(&ConfChange::default());
+        queue.add_proposal(expired);
+        queue.add_proposal(pending);
+
+        let expired = queue.remove_expired(Instant::now());
+        assert_eq!(expired.len(), 1);
+        for proposal in expired {
+            proposal.fail(ProposalError::Timeout);
+        }
+        assert_eq!(rx1.try_recv().unwrap().unwrap_err(), ProposalError::Timeout);
+        assert!(rx2.try_recv().is_err());
+        assert!(queue.remove_proposal().is_some());
+        assert!(queue.remove_proposal().is_none());
+    }
+
+    #[test]
+    fn test_waker() {
+        let mut queue = ProposalQueue::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        queue.clone().set_waker(tx);
+
+        let (proposal, _rx) = Proposal::conf_change(&ConfChange::default());
+        queue.add_proposal(proposal);
+        assert!(matches!(rx.try_recv(), Ok(PeerMsg::Proposals)));
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use raft::{prelude::*};
+use raft::storage::MemStorage;
+use protobuf::Message as PbMessage;
+
+// RaftStorage is a raft::Storage which the Raft loop writes through when handling a Ready.
+// Every write must be durable when the call returns: messages are only sent after that.
+pub trait RaftStorage: Storage {
+    // appends entries to the log. Existing entries from the index of the first appended entry are discarded.
+    fn append(&mut self, entries: &[Entry]) -> raft::Result<()>;
+
+    // replaces the whole log by the snapshot
+    fn apply_snapshot(&mut self, snapshot: Snapshot) -> raft::Result<()>;
+
+    fn set_hard_state(&mut self, hs: HardState) -> raft::Result<()>;
+
+    // records the configuration. During a membership change, cs is the configuration before the change, and
+    // pending_membership_change holds the configuration after it with the index of the entry which started it,
+    // so a restarted node is still in the joint configuration and can finalize the change
+    fn set_conf_state(&mut self, cs: ConfState, pending_membership_change: Option<(ConfState, u64)>) -> raft::Result<()>;
+
+    // records a snapshot of the state machine, which applied all entries up to index.
+    // The log up to index is discarded: raft sends the snapshot to the followers which are behind it
+    fn create_snapshot(&mut self, index: u64, cs: ConfState, data: Vec<u8>) -> raft::Result<()>;
+
+    // size in bytes taken by the log
+    fn log_bytes(&self) -> raft::Result<u64>;
+}
+
+// MemStorage keeps everything in memory, which is only useful for testing
+impl RaftStorage for MemStorage {
+    fn append(&mut self, entries: &[Entry]) -> raft::Result<()> {
+        self.wl().append(entries)
+    }
+
+    fn apply_snapshot(&mut self, snapshot: Snapshot) -> raft::Result<()> {
+        self.wl().apply_snapshot(snapshot)
+    }
+
+    fn set_hard_state(&mut self, hs: HardState) -> raft::Result<()> {
+        *self.wl().mut_hard_state() = hs;
+        Ok(())
+    }
+
+    fn set_conf_state(&mut self, cs: ConfState, pending_membership_change: Option<(ConfState, u64)>) -> raft::Result<()> {
+        self.wl().set_conf_state(cs, pending_membership_change);
+        Ok(())
+    }
+
+    // the whole log is kept in memory: raft never has to send a snapshot
+    fn create_snapshot(&mut self, _index: u64, _cs: ConfState, _data: Vec<u8>) -> raft::Result<()> {
+        Ok(())
+    }
+
+    fn log_bytes(&self) -> raft::Result<u64> {
+        let entries = self.entries(self.first_index()?, self.last_index()? + 1, None)?;
+        Ok(entries.iter().map(|e| u64::from(e.compute_size())).sum())
+    }
+}
@@ -1,13 +1,16 @@
 use raft::{RawNode, Config};
 use crate::network_outbound::NetworkOutbound;
 use crate::peer_sender::PeerSender;
-use crate::msg::Proposal;
+use crate::msg::{Proposal, ProposalError, ProposalResult};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::collections::VecDeque;
 
 use protobuf::Message as PbMessage;
 use raft::{prelude::*, StateRole};
 use crate::proposal_queue::ProposalQueue;
+use crate::compaction::LogMetrics;
+use command::Request;
 
 pub trait RaftEngine {
     fn insert_data(&mut self);
@@ -17,15 +20,34 @@ pub trait RaftEngine {
 pub struct RaftStore {
     id: u64,
     proposal_queue: ProposalQueue,
+    metrics: Arc<Mutex<LogMetrics>>,
+    leader_id: Arc<AtomicU64>,
 }
 
 impl RaftStore {
-    pub fn new(id: u64, proposal_queue: ProposalQueue) -> Self {
+    pub fn new(id: u64, proposal_queue: ProposalQueue, metrics: Arc<Mutex<LogMetrics>>, leader_id: Arc<AtomicU64>) -> Self {
         RaftStore {
             id,
             proposal_queue,
+            metrics,
+            leader_id,
         }
     }
+
+    // id of the leader known by this node, 0 during an election. It may be stale as soon as it's read
+    pub fn leader_id(&self) -> u64 {
+        self.leader_id.load(Ordering::SeqCst)
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader_id() == self.id
+    }
+
+    // metrics of the Raft log, updated on every compaction
+    pub fn log_metrics(&self) -> LogMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
     fn insert_data(&mut self) {
         unimplemented!()
     }
@@ -33,5 +55,70 @@ impl RaftStore {
     pub fn join(&mut self, leader_id: u64) {
 
     }
+
+    // propose appends the request to the Raft log, and waits until it's applied.
+    // The proposals still waiting when the node stops fail with ProposalError::Stopped
+    pub fn propose(&mut self, request: Request) -> ProposalResult {
+        let (proposal, rx) = Proposal::normal(request);
+        self.proposal_queue.add_proposal(proposal);
+        rx.recv().unwrap_or(Err(ProposalError::Stopped))
+    }
+
+    // read_index waits until the local state machine is up to date with the leader, without going through
+    // the Raft log. A query made afterwards is linearizable.
+    pub fn read_index(&self) -> Result<(), ProposalError> {
+        let (proposal, rx) = Proposal::read_index();
+        self.proposal_queue.clone().add_proposal(proposal);
+        rx.recv().unwrap_or(Err(ProposalError::Stopped)).map(|_| ())
+    }
+
+    // barrier appends an empty request to the log and waits until it's applied. The node must be the leader:
+    // the request isn't forwarded, as this node's state machine must have applied it. Everything committed
+    // before the barrier is then visible to a local query
+    pub fn barrier(&self) -> Result<(), ProposalError> {
+        let request = Request { transaction: false, statements: Box::new([]) };
+        let (mut proposal, rx) = Proposal::normal(request);
+        proposal.forwarded = true;
+        self.proposal_queue.clone().add_proposal(proposal);
+        rx.recv().unwrap_or(Err(ProposalError::Stopped)).map(|_| ())
+    }
+
+    // add_node adds the node to the voters of the cluster, and waits until the change is applied
+    pub fn add_node(&mut self, id: u64) -> ProposalResult {
+        self.conf_change(ConfChangeType::AddNode, id)
+    }
+
+    // remove_node removes the node from the cluster, and waits until the change is applied
+    pub fn remove_node(&mut self, id: u64) -> ProposalResult {
+        self.conf_change(ConfChangeType::RemoveNode, id)
+    }
+
+    fn conf_change(&mut self, change_type: ConfChangeType, id: u64) -> ProposalResult {
+        let mut cc = ConfChange::default();
+        cc.node_id = id;
+        cc.set_change_type(change_type);
+        let (proposal, rx) = Proposal::conf_change(&cc);
+        self.proposal_queue.add_proposal(proposal);
+        rx.recv().unwrap_or(Err(ProposalError::Stopped))
+    }
+
+    // change_membership replaces the voters and the learners of the cluster in one step, using joint consensus.
+    // It waits until the leader finalized the change
+    pub fn change_membership(&mut self, voters: Vec<u64>, learners: Vec<u64>) -> ProposalResult {
+        let mut cc = ConfChange::default();
+        cc.set_change_type(ConfChangeType::BeginMembershipChange);
+        cc.mut_configuration().nodes = voters;
+        cc.mut_configuration().learners = learners;
+        let (proposal, rx) = Proposal::conf_change(&cc);
+        self.proposal_queue.add_proposal(proposal);
+        rx.recv().unwrap_or(Err(ProposalError::Stopped))
+    }
+
+    // transfer_leader asks the leader to hand its leadership over to transferee, and waits until it's done
+    pub fn transfer_leader(&mut self, transferee: u64) -> ProposalResult {
+        let (proposal, rx) = Proposal::transfer_leader(transferee);
+        self.proposal_queue.add_proposal(proposal);
+        rx.recv().unwrap_or(Err(ProposalError::Stopped))
+    }
 }
 
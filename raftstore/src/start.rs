@@ -4,43 +4,126 @@ use crate::msg::Proposal;
 use crate::raft_store::RaftStore;
 use crate::poller::Poller;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU64;
 use std::collections::VecDeque;
 
 use raft::{prelude::*};
 use protobuf::Message as PbMessage;
 use crate::proposal_queue::ProposalQueue;
 use crate::network_inbound::NetworkInbound;
+use crate::disk_storage::DiskStorage;
+use crate::state_machine::StateMachine;
+use crate::config::RaftConfig;
+use raft::storage::MemStorage;
+use raft::StorageError;
+use dust_util::box_err;
+use std::path::Path;
 
-// start a raft node
-pub fn start<A, B>(
+// start a raft node, its Raft log is kept under raft_dir and committed entries are applied to state_machine.
+// The config is validated before anything is opened.
+// A restarted node resumes from its Raft state on disk: it rejoins the cluster it was part of and only the
+// committed entries which the state machine didn't apply yet are applied
+pub fn start<A, B, P, M>(
     id: u64,
+    raft_dir: P,
+    mut state_machine: M,
+    config: &RaftConfig,
     network_inbound: NetworkInbound<A, B>,
     network_outbound: NetworkOutbound,
+    proposal_queue: ProposalQueue,
     initialize: bool,
-) -> (RaftStore, Poller)
+) -> raft::Result<(RaftStore, Poller)>
     where
         A: PeerSender<Message=Message> + Send + 'static,
         B: PeerSender<Message=Proposal> + Send + 'static,
+        P: AsRef<Path>,
+        M: StateMachine + Send + 'static,
 {
-    let proposal_queue = ProposalQueue::new();
-    let raft_store = RaftStore::new(id, proposal_queue.clone());
-    let poller = Poller::start(id, proposal_queue, network_inbound, network_outbound, initialize);
-    (raft_store, poller)
+    config.validate()?;
+    let storage = DiskStorage::open(raft_dir)?;
+    let applied = recover(&storage, &mut state_machine)?;
+    let metrics = Arc::new(Mutex::new(Default::default()));
+    let leader_id = Arc::new(AtomicU64::new(0));
+    let raft_store = RaftStore::new(id, proposal_queue.clone(), metrics.clone(), leader_id.clone());
+    let poller = Poller::start(
+        id, storage, state_machine, applied, config, metrics, leader_id,
+        proposal_queue, network_inbound, network_outbound, initialize);
+    Ok((raft_store, poller))
 }
 
-// start a raft node
-pub fn start_for_testing<A, B>(
+// start a raft node configured by the `[raft]` table of the node's config file at config_path
+pub fn start_from_config_file<A, B, P, C, M>(
     id: u64,
+    raft_dir: P,
+    config_path: C,
+    state_machine: M,
     network_inbound: NetworkInbound<A, B>,
     network_outbound: NetworkOutbound,
     proposal_queue: ProposalQueue,
     initialize: bool,
+) -> raft::Result<(RaftStore, Poller)>
+    where
+        A: PeerSender<Message=Message> + Send + 'static,
+        B: PeerSender<Message=Proposal> + Send + 'static,
+        P: AsRef<Path>,
+        C: AsRef<Path>,
+        M: StateMachine + Send + 'static,
+{
+    let config = RaftConfig::from_file(config_path).map_err(|e| raft::Error::ConfigInvalid(e.to_string()))?;
+    start(id, raft_dir, state_machine, &config, network_inbound, network_outbound, proposal_queue, initialize)
+}
+
+// start a raft node with an in-memory Raft log
+pub fn start_for_testing<A, B, M>(
+    id: u64,
+    state_machine: M,
+    network_inbound: NetworkInbound<A, B>,
+    network_outbound: NetworkOutbound,
+    proposal_queue: ProposalQueue,
+    config: &RaftConfig,
+    initialize: bool,
 ) -> (RaftStore, Poller)
     where
         A: PeerSender<Message=Message> + Send + 'static,
         B: PeerSender<Message=Proposal> + Send + 'static,
+        M: StateMachine + Send + 'static,
 {
-    let raft_store = RaftStore::new(id, proposal_queue.clone());
-    let poller = Poller::start(id, proposal_queue, network_inbound, network_outbound, initialize);
+    let metrics = Arc::new(Mutex::new(Default::default()));
+    let leader_id = Arc::new(AtomicU64::new(0));
+    let raft_store = RaftStore::new(id, proposal_queue.clone(), metrics.clone(), leader_id.clone());
+    let poller = Poller::start(
+        id, MemStorage::new(), state_machine, 0, config, metrics, leader_id,
+        proposal_queue, network_inbound, network_outbound, initialize);
     (raft_store, poller)
 }
+
+// returns the index of the last entry applied to the state machine. A state machine behind the latest snapshot
+// (e.g.: an in-memory database) is restored from the snapshot first.
+fn recover<S, M>(storage: &S, state_machine: &mut M) -> raft::Result<u64>
+    where
+        S: Storage,
+        M: StateMachine,
+{
+    let snapshot = storage.snapshot()?;
+    let snapshot_index = snapshot.get_metadata().index;
+    let committed = storage.initial_state()?.hard_state.commit.max(snapshot_index);
+    let applied = state_machine.applied_index().map_err(state_machine_err)?;
+    if applied > committed {
+        // the log doesn't hold the entries the state machine applied, e.g.: the Raft directory was removed
+        return Err(state_machine_err(format!("applied index {} is ahead of the commit index {}", applied, committed)));
+    }
+    if applied >= snapshot_index {
+        return Ok(applied);
+    }
+
+    // the snapshot bootstrapping the cluster holds no state
+    if !snapshot.data.is_empty() {
+        state_machine.restore(&snapshot.data).map_err(state_machine_err)?;
+        state_machine.set_applied_index(snapshot_index).map_err(state_machine_err)?;
+    }
+    Ok(snapshot_index)
+}
+
+fn state_machine_err(e: String) -> raft::Error {
+    raft::Error::Store(StorageError::Other(box_err!("state machine: {}", e)))
+}
@@ -0,0 +1,36 @@
+use command::{Request, Response};
+
+// StateMachine is where the committed entries end up, e.g.: the SQLite database.
+// It is only driven by the Raft loop, hence raftstore doesn't depend on any concrete database.
+pub trait StateMachine {
+    // applies a committed request. Errors of single statements are reported inside their Response.
+    fn apply(&mut self, req: &Request) -> Result<Vec<Response>, String>;
+
+    // returns the whole state, which can be restored on any node
+    fn snapshot(&self) -> Result<Vec<u8>, String>;
+
+    // replaces the whole state by the data of a snapshot
+    fn restore(&mut self, data: &[u8]) -> Result<(), String>;
+
+    // index of the last entry applied to the state, kept with the state so a restarted node only applies the entries after it.
+    // A state which isn't kept across restarts returns 0: it's restored from the latest snapshot and the log instead
+    fn applied_index(&self) -> Result<u64, String> {
+        Ok(0)
+    }
+
+    // records the index of the last applied entry, for the entries which don't change the state,
+    // e.g.: the empty entry of a new leader
+    fn set_applied_index(&mut self, _index: u64) -> Result<(), String> {
+        Ok(())
+    }
+
+    // applies the requests of the committed entry at index, and records index as the applied index.
+    // A state kept across restarts must do both at once, otherwise a crash in between applies the requests
+    // again on restart. The default applies the requests one by one, then records the index.
+    // An error fails the whole entry, the error of a single request is reported in its result
+    fn apply_entry(&mut self, index: u64, requests: &[Request]) -> Result<Vec<Result<Vec<Response>, String>>, String> {
+        let results = requests.iter().map(|req| self.apply(req)).collect();
+        self.set_applied_index(index)?;
+        Ok(results)
+    }
+}
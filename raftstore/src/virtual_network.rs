@@ -2,9 +2,9 @@ use crate::peer_sender::ChannelSender;
 
 use raft::{prelude::*};
 use crate::network_inbound::NetworkInbound;
-use crate::msg::Proposal;
+use crate::msg::{PeerMsg, Proposal};
 use std::sync::mpsc::{Receiver, Sender, RecvTimeoutError, RecvError, SyncSender, TryRecvError};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::network_outbound::NetworkOutbound;
 use std::sync::{mpsc, Mutex, Arc};
 use crate::proposal_queue::ProposalQueue;
@@ -27,6 +27,9 @@ pub struct VirtualNetwork {
 
     stop_snd: SyncSender<()>,
 
+    // links of the reconnected nodes, handed over to the running network
+    reconnect_snd: Sender<Links>,
+
     // map from node_id -> NetworkInbound (will transfer ownership to caller)
     pub network_inbounds: HashMap<u64, NetworkInbound<ChannelSender<Message>, ChannelSender<Proposal>>>,
 
@@ -35,6 +38,9 @@ pub struct VirtualNetwork {
 
     pub proposal_queues: HashMap<u64, ProposalQueue>,
 
+    // nodes cut off from all other nodes
+    isolated: Arc<Mutex<HashSet<u64>>>,
+
     network_core: Arc<Mutex<NetworkCore>>,
 }
 
@@ -44,22 +50,37 @@ struct NetworkCore {
 
     stop_rcv: Receiver<()>,
 
+    reconnect_rcv: Receiver<Links>,
+
     // map from sender_id -> (receiver_id -> Message) : received from network_inbounds
     mm_receivers: HashMap<u64, HashMap<u64, Receiver<Message>>>,
 
     // map from sender_id -> (receiver_id -> Proposal): received from network_inbounds
     mm_proposal_receivers: HashMap<u64, HashMap<u64, Receiver<Proposal>>>,
 
-    // map from sender_id -> (receiver_id -> Sender<Message> object)
+    // map from sender_id -> (receiver_id -> Sender<PeerMsg> object)
     // use messages from mm_receivers as  the source then sending messages to network_outbound
-    mm_senders: HashMap<u64, HashMap<u64, Sender<Message>>>,
+    mm_senders: HashMap<u64, HashMap<u64, Sender<PeerMsg>>>,
 
     proposal_queues: HashMap<u64, ProposalQueue>,
+
+    isolated: Arc<Mutex<HashSet<u64>>>,
+}
+
+// the links of a restarted node, which replace the ones of its previous run
+struct Links {
+    node: u64,
+    // map from receiver_id -> Message / Proposal sent by the node
+    receivers: HashMap<u64, Receiver<Message>>,
+    proposal_receivers: HashMap<u64, Receiver<Proposal>>,
+    // mailbox of the node
+    sender: Sender<PeerMsg>,
 }
 
 impl VirtualNetwork {
     pub fn new(n: u64) -> VirtualNetwork {
         let (stop_snd, stop_rcv) = mpsc::sync_channel(0);
+        let (reconnect_snd, reconnect_rcv) = mpsc::channel();
 
         let mut network_inbounds = HashMap::new();
         let mut network_outbounds = HashMap::new();
@@ -71,40 +92,22 @@ impl VirtualNetwork {
         let mut proposal_queues = HashMap::new();
 
         for node in 1..n + 1 {
-            mm_receivers.insert(node, HashMap::<u64, Receiver<Message>>::new());
-            mm_proposal_receivers.insert(node, HashMap::<u64, Receiver<Proposal>>::new());
-            mm_senders.insert(node, HashMap::<u64, Sender<Message>>::new());
+            mm_senders.insert(node, HashMap::<u64, Sender<PeerMsg>>::new());
             proposal_queues.insert(node, ProposalQueue::new());
         }
 
         // create network_inbound with the direction from -> all nodes
         for from in 1..n + 1 {
-            let mut network_inbound =
-                NetworkInbound::<ChannelSender<Message>, ChannelSender<Proposal>>::new();
-
-            for to in 1..n + 1 {
-                if from == to {
-                    continue;
-                }
-
-                let (internal_sender, internal_receiver) =
-                    ChannelSender::<Message>::new(from, to);
-                let (proposal_sender, proposal_receiver) =
-                    ChannelSender::<Proposal>::new(from, to);
-
-                network_inbound.add_conn(internal_sender, proposal_sender);
-
-                mm_receivers.get_mut(&from).unwrap().insert(to, internal_receiver);
-                mm_proposal_receivers.get_mut(&from).unwrap().insert(to, proposal_receiver);
-            }
-
+            let (network_inbound, receivers, proposal_receivers) = connect(from, n);
+            mm_receivers.insert(from, receivers);
+            mm_proposal_receivers.insert(from, proposal_receivers);
             network_inbounds.insert(from, network_inbound);
         }
 
         // create network outbound with direction all nodes -> to
         for to in 1..n + 1 {
-            let (tx, rx) = mpsc::channel::<Message>();
-            let mut network_outbound = NetworkOutbound::new(rx);
+            let network_outbound = NetworkOutbound::new();
+            let tx = network_outbound.sender();
             network_outbounds.insert(to, network_outbound);
 
             for from in 1..n + 1 {
@@ -115,16 +118,40 @@ impl VirtualNetwork {
             }
         }
 
+        let isolated = Arc::new(Mutex::new(HashSet::new()));
         let network_core = Arc::new(Mutex::new(NetworkCore {
             n,
             stop_rcv,
+            reconnect_rcv,
             mm_receivers,
             mm_proposal_receivers,
             mm_senders,
             proposal_queues: proposal_queues.clone(),
+            isolated: isolated.clone(),
         }));
 
-        VirtualNetwork { n, stop_snd, network_core, network_inbounds, network_outbounds, proposal_queues }
+        VirtualNetwork {
+            n, stop_snd, reconnect_snd, network_core, network_inbounds, network_outbounds, proposal_queues, isolated,
+        }
+    }
+
+    // creates new links for a node which is restarted, the running network switches to them.
+    // Messages sent to the node while it's stopped are lost
+    pub fn reconnect(&self, node: u64) -> (NetworkInbound<ChannelSender<Message>, ChannelSender<Proposal>>, NetworkOutbound) {
+        let (network_inbound, receivers, proposal_receivers) = connect(node, self.n);
+        let network_outbound = NetworkOutbound::new();
+        let sender = network_outbound.sender();
+        let _ = self.reconnect_snd.send(Links { node, receivers, proposal_receivers, sender });
+        (network_inbound, network_outbound)
+    }
+
+    // isolate drops every message and proposal sent from or to the node, until it's healed
+    pub fn isolate(&self, node: u64) {
+        self.isolated.lock().unwrap().insert(node);
+    }
+
+    pub fn heal(&self, node: u64) {
+        self.isolated.lock().unwrap().remove(&node);
     }
 
     pub fn start(&mut self) {
@@ -154,6 +181,18 @@ impl NetworkCore {
                 _ => {}
             }
 
+            while let Ok(links) = self.reconnect_rcv.try_recv() {
+                for from in 1..self.n + 1 {
+                    if from != links.node {
+                        self.mm_senders.get_mut(&from).unwrap().insert(links.node, links.sender.clone());
+                    }
+                }
+                self.mm_receivers.insert(links.node, links.receivers);
+                self.mm_proposal_receivers.insert(links.node, links.proposal_receivers);
+            }
+
+            let mut idle = true;
+            let isolated = self.isolated.lock().unwrap().clone();
             for from in 1..self.n + 1 {
                 let receivers = self.mm_receivers.get(&from).unwrap();
 
@@ -165,46 +204,74 @@ impl NetworkCore {
 
                     // println!("virtual_network: try to send from {} to {}", from, to);
                     let receiver = receivers.get(&to).unwrap();
-                    match receiver.recv_timeout(Duration::from_millis(10)) {
-                        Err(RecvTimeoutError::Timeout) => {}
-                        Err(RecvTimeoutError::Disconnected) => {
-                            // TODO: cleanup all resource her
-                            println!("ERRROR!!!. from {} to {}", from, to);
-                            break;
+                    match receiver.try_recv() {
+                        Err(TryRecvError::Empty) => {}
+                        // the node is stopped, until it's reconnected
+                        Err(TryRecvError::Disconnected) => {}
+                        Ok(_) if isolated.contains(&from) || isolated.contains(&to) => {
+                            idle = false;
                         }
                         Ok(msg) => {
+                            idle = false;
                             // println!("virtual_network: send from {} to {} with msg [{:?}]", from, to, msg);
                             let sender = self
                                 .mm_senders
                                 .get_mut(&from).unwrap()
                                 .get_mut(&to).unwrap();
-                            sender.send(msg);
+                            sender.send(PeerMsg::Raft(msg));
                         }
                     }
                 }
 
-                // send all proposal messages: TODO: we don't have this mechanism yet
+                // forward the proposals sent by [from] to the proposal queue of [to]
                 let receivers = self.mm_proposal_receivers.get(&from).unwrap();
                 for to in 1..self.n + 1 {
                     if from == to {
                         continue;
                     }
 
-                    // let mut proposal_queue = self.proposal_queues.get_mut(&to).unwrap();
-                    // let receiver = receivers.get(&to).unwrap();
-                    // match receiver.recv_timeout(Duration::from_millis(10)) {
-                    //     Err(RecvTimeoutError::Timeout) => {}
-                    //     Err(RecvTimeoutError::Disconnected) => {
-                    //         // TODO: cleanup all resource here
-                    //         break;
-                    //     }
-                    //     Ok(proposal) => {
-                    //         println!("virtual_network: send from {} to {} with proposal [{:?}]", from, to, proposal);
-                    //         proposal_queue.add_proposal(proposal);
-                    //     }
-                    // }
+                    let proposal_queue = self.proposal_queues.get_mut(&to).unwrap();
+                    let receiver = receivers.get(&to).unwrap();
+                    while let Ok(proposal) = receiver.try_recv() {
+                        idle = false;
+                        if isolated.contains(&from) || isolated.contains(&to) {
+                            // the proposer sees the proposal as dropped
+                            continue;
+                        }
+                        proposal_queue.add_proposal(proposal);
+                    }
                 }
             }
+
+            // nothing to forward on any link, wait a bit instead of spinning
+            if idle {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+}
+
+// creates the links from the node to all other nodes
+fn connect(
+    from: u64,
+    n: u64,
+) -> (NetworkInbound<ChannelSender<Message>, ChannelSender<Proposal>>, HashMap<u64, Receiver<Message>>, HashMap<u64, Receiver<Proposal>>) {
+    let mut network_inbound = NetworkInbound::<ChannelSender<Message>, ChannelSender<Proposal>>::new();
+    let mut receivers = HashMap::new();
+    let mut proposal_receivers = HashMap::new();
+
+    for to in 1..n + 1 {
+        if from == to {
+            continue;
         }
+
+        let (internal_sender, internal_receiver) = ChannelSender::<Message>::new(from, to);
+        let (proposal_sender, proposal_receiver) = ChannelSender::<Proposal>::new(from, to);
+
+        network_inbound.add_conn(internal_sender, proposal_sender);
+
+        receivers.insert(to, internal_receiver);
+        proposal_receivers.insert(to, proposal_receiver);
     }
-}
\ No newline at end of file
+    (network_inbound, receivers, proposal_receivers)
+}
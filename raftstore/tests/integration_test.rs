@@ -1,15 +1,72 @@
 use raftstore::*;
 use raft::{prelude::*};
+use command::{Parameter, Parameters, Request, RequestBatch, Response, Statement};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
 
-#[test]
-fn integration_test() {
-    let n = 5;
-    let leader_id = 1;
+// MemStateMachine records every applied request. Its clones share the state, which survives a restart of the node
+#[derive(Clone, Default)]
+struct MemStateMachine {
+    applied: Arc<Mutex<Vec<Request>>>,
+    applied_index: Arc<Mutex<u64>>,
+}
 
-    let mut network = VirtualNetwork::new(n);
+impl StateMachine for MemStateMachine {
+    fn apply(&mut self, req: &Request) -> Result<Vec<Response>, String> {
+        let mut applied = self.applied.lock().unwrap();
+        applied.push(req.clone());
+        Ok(req.statements.iter().map(|_| Response {
+            last_insert_id: applied.len() as i64,
+            rows_affected: 1,
+            error: "".to_string(),
+        }).collect())
+    }
+
+    // the snapshot holds every applied request
+    fn snapshot(&self) -> Result<Vec<u8>, String> {
+        let mut batch = RequestBatch::new();
+        for req in self.applied.lock().unwrap().iter() {
+            batch.push(req);
+        }
+        Ok(batch.encode())
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<(), String> {
+        *self.applied.lock().unwrap() = RequestBatch::decode(data)?;
+        Ok(())
+    }
+
+    fn applied_index(&self) -> Result<u64, String> {
+        Ok(*self.applied_index.lock().unwrap())
+    }
+
+    fn set_applied_index(&mut self, index: u64) -> Result<(), String> {
+        *self.applied_index.lock().unwrap() = index;
+        Ok(())
+    }
+}
+
+fn insert_request(i: i64) -> Request {
+    Request {
+        transaction: false,
+        statements: Box::new([Statement {
+            sql: String::from("INSERT INTO foo(id, name) VALUES(?, ?)"),
+            parameters: Parameters::Positional(Box::new([Parameter::Integer(i), Parameter::Text(String::from("hello, world"))])),
+        }]),
+    }
+}
+
+// starts every node of the network, leader_id bootstraps the cluster
+fn start_nodes(network: &mut VirtualNetwork, leader_id: u64, config: &RaftConfig) -> (Vec<RaftStore>, Vec<Poller>, Vec<MemStateMachine>) {
     let mut raft_stores = Vec::new();
     let mut pollers = Vec::new();
+    let mut state_machines = Vec::new();
 
+    let n = network.network_inbounds.len() as u64;
     for node in 1..n + 1 {
         let mut initialize = false;
         if node == leader_id {
@@ -19,16 +76,59 @@ fn integration_test() {
         let network_inbound = network.network_inbounds.remove(&node).unwrap();
         let network_outbound = network.network_outbounds.remove(&node).unwrap();
         let proposal_queue = network.proposal_queues.get(&node).unwrap().clone();
+        let state_machine = MemStateMachine::default();
+        state_machines.push(state_machine.clone());
         let (raft_store, poller) = start_for_testing(
-            node, network_inbound, network_outbound,
-            proposal_queue, initialize);
+            node, state_machine, network_inbound, network_outbound,
+            proposal_queue, config, initialize);
         raft_stores.push(raft_store);
         pollers.push(poller);
     }
+    (raft_stores, pollers, state_machines)
+}
 
+// waits for the state machine to apply the requests 1 to n, each one once
+fn wait_applied(state_machine: &MemStateMachine, n: usize) {
+    let applied = &state_machine.applied;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while applied.lock().unwrap().len() < n && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let applied = applied.lock().unwrap();
+    assert_eq!(applied.len(), n);
+    for (i, req) in applied.iter().enumerate() {
+        assert_eq!(format!("{:?}", req), format!("{:?}", insert_request(i as i64 + 1)));
+    }
+}
+
+// starts the node with its Raft log under raft_dir
+fn start_on_disk(
+    network: &VirtualNetwork,
+    node: u64,
+    raft_dir: &Path,
+    state_machine: &MemStateMachine,
+    (network_inbound, network_outbound): (NetworkInbound<ChannelSender<Message>, ChannelSender<Proposal>>, NetworkOutbound),
+    config: &RaftConfig,
+    initialize: bool,
+) -> (RaftStore, Poller) {
+    let proposal_queue = network.proposal_queues.get(&node).unwrap().clone();
+    start(
+        node, raft_dir, state_machine.clone(), config,
+        network_inbound, network_outbound, proposal_queue, initialize).unwrap()
+}
+
+#[test]
+fn integration_test() {
+    // nodes 6 and 7 are spare nodes, joined later by a membership change
+    let n = 7;
+    let voters = 5;
+    let leader_id = 1;
+
+    let mut network = VirtualNetwork::new(n);
+    let (mut raft_stores, pollers, state_machines) = start_nodes(&mut network, leader_id, &RaftConfig::default());
     network.async_start();
 
-    for node in 2..n + 1 {
+    for node in 2..voters + 1 {
         // send proposal to leader
         let mut conf_change = ConfChange::default();
         conf_change.node_id = node;
@@ -37,27 +137,460 @@ fn integration_test() {
         network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
 
         let res = rx.recv().unwrap();
-        assert_eq!(res, true);
+        assert!(res.is_ok());
         println!("node {} joined successfully", node);
     }
 
     // send proposals to write message
     for i in 1..10 {
         println!("send message {}", i);
-        let (proposal, rx) = Proposal::normal(i, "hello, world".to_owned());
+        let (proposal, rx) = Proposal::normal(insert_request(i));
         network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
 
-        // After we got a response from `rx`, we can assume the put succeeded and following
-        // `get` operations can find the key-value pair.
+        // After we got a response from `rx`, the request has been applied to the state machine of the leader
         let res = rx.recv().unwrap();
-        assert_eq!(res, true);
+        assert_eq!(res.unwrap().len(), 1);
         println!("send message {} successfully", i);
     }
 
     println!("done sending messages");
+    // followers forward writes to the leader, which answers the client
+    let (proposal, rx) = Proposal::normal(insert_request(10));
+    network.proposal_queues.get_mut(&2).unwrap().add_proposal(proposal);
+    assert_eq!(rx.recv().unwrap().unwrap().len(), 1);
+
+    // a follower serves linearizable reads once it applied the read index given by the leader
+    assert!(raft_stores[2].read_index().is_ok());
+    assert_eq!(state_machines[2].applied.lock().unwrap().len(), 10);
+    let start = Instant::now();
+    for _ in 0..10 {
+        assert!(raft_stores[(leader_id - 1) as usize].read_index().is_ok());
+    }
+    println!("10 reads confirmed by a quorum took {:?}", start.elapsed());
+
+    // requests went through the raft log, so they were encoded and decoded on the way
+    let applied = state_machines[(leader_id - 1) as usize].applied.lock().unwrap();
+    assert_eq!(applied.len(), 10);
+    for (i, req) in applied.iter().enumerate() {
+        assert_eq!(format!("{:?}", req), format!("{:?}", insert_request(i as i64 + 1)));
+    }
+    drop(applied);
+
+    // other proposals are refused by followers, which name the leader
+    let mut conf_change = ConfChange::default();
+    conf_change.node_id = 2;
+    conf_change.set_change_type(ConfChangeType::RemoveNode);
+    let (proposal, rx) = Proposal::conf_change(&conf_change);
+    network.proposal_queues.get_mut(&2).unwrap().add_proposal(proposal);
+    assert_eq!(rx.recv().unwrap().unwrap_err(), ProposalError::NotLeader { leader_id });
+
+    // hand the leadership over to node 2, the former leader then forwards writes to it
+    assert!(raft_stores[(leader_id - 1) as usize].transfer_leader(2).is_ok());
+    let (proposal, rx) = Proposal::normal(insert_request(11));
+    network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
+    assert_eq!(rx.recv().unwrap().unwrap().len(), 1);
+    assert_eq!(state_machines[1].applied.lock().unwrap().len(), 11);
+
+    // replace nodes 4 and 5 by nodes 6 and 7 in one step
+    assert!(raft_stores[1].change_membership(vec![1, 2, 3, 6, 7], vec![]).is_ok());
+    let (proposal, rx) = Proposal::normal(insert_request(12));
+    network.proposal_queues.get_mut(&2).unwrap().add_proposal(proposal);
+    assert_eq!(rx.recv().unwrap().unwrap().len(), 1);
+
+    // the new voters catch up with the whole log
+    for node in vec![6, 7] {
+        let applied = &state_machines[node - 1].applied;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while applied.lock().unwrap().len() < 12 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(applied.lock().unwrap().len(), 12);
+    }
 
     network.stop();
     for poller in pollers {
         poller.stop();
     }
-}
\ No newline at end of file
+}
+
+// starts a cluster of n nodes, node 1 bootstraps it and adds the other ones
+fn start_cluster(n: u64, config: &RaftConfig) -> (VirtualNetwork, Vec<RaftStore>, Vec<Poller>) {
+    let leader_id = 1;
+    let mut network = VirtualNetwork::new(n);
+    let (raft_stores, pollers, _) = start_nodes(&mut network, leader_id, config);
+    network.async_start();
+
+    for node in 2..n + 1 {
+        let mut conf_change = ConfChange::default();
+        conf_change.node_id = node;
+        conf_change.set_change_type(ConfChangeType::AddNode);
+        let (proposal, rx) = Proposal::conf_change(&conf_change);
+        network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
+        assert!(rx.recv().unwrap().is_ok());
+    }
+    let (proposal, rx) = Proposal::normal(insert_request(1));
+    network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
+    assert_eq!(rx.recv().unwrap().unwrap().len(), 1);
+    (network, raft_stores, pollers)
+}
+
+// times n reads served by the leader
+fn time_reads(raft_store: &RaftStore, n: usize) -> Duration {
+    let start = Instant::now();
+    for _ in 0..n {
+        assert!(raft_store.read_index().is_ok());
+    }
+    start.elapsed()
+}
+
+#[test]
+fn lease_read_test() {
+    let n = 3;
+    let leader_id = 1;
+    let config = RaftConfig { check_quorum: true, lease_read: true, ..Default::default() };
+    let election_timeout = Duration::from_millis(config.tick_interval_ms * config.election_tick as u64);
+
+    // a ReadIndex read waits for a heartbeat round trip to the followers, a read under the lease doesn't
+    let (network, raft_stores, pollers) = start_cluster(n, &RaftConfig { lease_read: false, ..config.clone() });
+    let read_index_elapsed = time_reads(&raft_stores[(leader_id - 1) as usize], 10);
+    network.stop();
+    for poller in pollers {
+        poller.stop();
+    }
+
+    let (mut network, raft_stores, pollers) = start_cluster(n, &config);
+    let lease_elapsed = time_reads(&raft_stores[(leader_id - 1) as usize], 10);
+    assert!(lease_elapsed < read_index_elapsed, "lease reads: {:?}, ReadIndex reads: {:?}", lease_elapsed, read_index_elapsed);
+
+    // the partitioned leader steps down once it doesn't hear from a quorum within an election timeout,
+    // then it stops serving reads
+    network.isolate(leader_id);
+    let deadline = Instant::now() + 3 * election_timeout;
+    while raft_stores[(leader_id - 1) as usize].leader_id() == leader_id && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_ne!(raft_stores[(leader_id - 1) as usize].leader_id(), leader_id);
+    let (mut proposal, rx) = Proposal::read_index();
+    proposal.deadline = Instant::now() + election_timeout;
+    network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
+    assert_eq!(rx.recv().unwrap().unwrap_err(), ProposalError::Timeout);
+
+    // the other nodes elect a new leader, which serves reads
+    let deadline = Instant::now() + 3 * election_timeout;
+    while [0, leader_id].contains(&raft_stores[1].leader_id()) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(![0, leader_id].contains(&raft_stores[1].leader_id()));
+    assert!(raft_stores[1].read_index().is_ok());
+
+    network.stop();
+    for poller in pollers {
+        poller.stop();
+    }
+}
+
+#[test]
+fn batch_test() {
+    let leader_id = 1;
+    let mut network = VirtualNetwork::new(1);
+    let (_raft_stores, pollers, state_machines) = start_nodes(&mut network, leader_id, &RaftConfig::default());
+    network.async_start();
+
+    // writes queued together are applied in order, and each client gets the response of its own request
+    let receivers: Vec<_> = (1..6).map(|i| {
+        let (proposal, rx) = Proposal::normal(insert_request(i));
+        network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
+        rx
+    }).collect();
+    for (i, rx) in receivers.into_iter().enumerate() {
+        let responses = rx.recv().unwrap().unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].last_insert_id, i as i64 + 1);
+    }
+
+    let applied = state_machines[0].applied.lock().unwrap();
+    for (i, req) in applied.iter().enumerate() {
+        assert_eq!(format!("{:?}", req), format!("{:?}", insert_request(i as i64 + 1)));
+    }
+    drop(applied);
+
+    network.stop();
+    for poller in pollers {
+        poller.stop();
+    }
+}
+
+#[test]
+fn config_file_test() {
+    let dir = TempDir::new().unwrap();
+    let config_path = dir.path().join("dust.toml");
+    let state_machine = MemStateMachine::default();
+    let mut network = VirtualNetwork::new(1);
+    let proposal_queue = network.proposal_queues.get(&1).unwrap().clone();
+
+    // an invalid config file is refused before the node starts
+    std::fs::write(&config_path, "[raft]\nlease_read = true\n").unwrap();
+    let started = start_from_config_file(
+        1, dir.path().join("raft"), &config_path, state_machine.clone(),
+        network.network_inbounds.remove(&1).unwrap(), network.network_outbounds.remove(&1).unwrap(),
+        proposal_queue.clone(), true);
+    assert!(matches!(started, Err(raft::Error::ConfigInvalid(_))));
+
+    std::fs::write(&config_path, "[raft]\ntick_interval_ms = 10\nelection_tick = 20\n").unwrap();
+    let (network_inbound, network_outbound) = network.reconnect(1);
+    let (mut raft_store, poller) = start_from_config_file(
+        1, dir.path().join("raft"), &config_path, state_machine.clone(),
+        network_inbound, network_outbound, proposal_queue, true).unwrap();
+    network.async_start();
+    assert!(raft_store.propose(insert_request(1)).is_ok());
+    wait_applied(&state_machine, 1);
+
+    network.stop();
+    poller.stop();
+}
+
+#[test]
+fn restart_test() {
+    let n = 3;
+    let leader_id = 1;
+    let dirs: Vec<_> = (0..n).map(|_| TempDir::new().unwrap()).collect();
+    let state_machines: Vec<_> = (0..n).map(|_| MemStateMachine::default()).collect();
+
+    let mut network = VirtualNetwork::new(n);
+    let mut raft_stores = Vec::new();
+    let mut pollers = HashMap::new();
+    for node in 1..n + 1 {
+        let i = (node - 1) as usize;
+        let network_inbound = network.network_inbounds.remove(&node).unwrap();
+        let network_outbound = network.network_outbounds.remove(&node).unwrap();
+        let (raft_store, poller) = start_on_disk(
+            &network, node, dirs[i].path(), &state_machines[i], (network_inbound, network_outbound), &RaftConfig::default(), node == leader_id);
+        raft_stores.push(raft_store);
+        pollers.insert(node, poller);
+    }
+    network.async_start();
+
+    for node in 2..n + 1 {
+        let mut conf_change = ConfChange::default();
+        conf_change.node_id = node;
+        conf_change.set_change_type(ConfChangeType::AddNode);
+        let (proposal, rx) = Proposal::conf_change(&conf_change);
+        network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
+        assert!(rx.recv().unwrap().is_ok());
+    }
+    for i in 1..4 {
+        assert!(raft_stores[(leader_id - 1) as usize].propose(insert_request(i)).is_ok());
+    }
+
+    // the cluster keeps committing while a follower is down
+    let follower = 3;
+    let poller = pollers.remove(&follower).unwrap();
+    poller.stop();
+    poller.join();
+    for i in 4..7 {
+        assert!(raft_stores[(leader_id - 1) as usize].propose(insert_request(i)).is_ok());
+    }
+
+    // the follower rejoins without a conf change, it only applies the entries it missed
+    let i = (follower - 1) as usize;
+    let (network_inbound, network_outbound) = network.reconnect(follower);
+    let (raft_store, poller) = start_on_disk(
+        &network, follower, dirs[i].path(), &state_machines[i], (network_inbound, network_outbound), &RaftConfig::default(), false);
+    raft_stores[i] = raft_store;
+    pollers.insert(follower, poller);
+    wait_applied(&state_machines[i], 6);
+
+    // the other nodes elect a new leader while the leader is down
+    let poller = pollers.remove(&leader_id).unwrap();
+    poller.stop();
+    poller.join();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while [0, leader_id].contains(&raft_stores[1].leader_id()) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let new_leader_id = raft_stores[1].leader_id();
+    assert!(![0, leader_id].contains(&new_leader_id));
+    assert!(raft_stores[(new_leader_id - 1) as usize].propose(insert_request(7)).is_ok());
+
+    // the former leader comes back as a follower, initialize is ignored once the node has a Raft log
+    let i = (leader_id - 1) as usize;
+    let (network_inbound, network_outbound) = network.reconnect(leader_id);
+    let (raft_store, poller) = start_on_disk(
+        &network, leader_id, dirs[i].path(), &state_machines[i], (network_inbound, network_outbound), &RaftConfig::default(), true);
+    raft_stores[i] = raft_store;
+    pollers.insert(leader_id, poller);
+    wait_applied(&state_machines[i], 7);
+    assert!(raft_stores[(new_leader_id - 1) as usize].propose(insert_request(8)).is_ok());
+    for state_machine in &state_machines {
+        wait_applied(state_machine, 8);
+    }
+
+    network.stop();
+    for poller in pollers.values() {
+        poller.stop();
+    }
+}
+
+// waits for the state machine to apply an entry after index, and returns the new applied index
+fn wait_applied_after(state_machine: &MemStateMachine, index: u64) -> u64 {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while state_machine.applied_index().unwrap() <= index && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let applied_index = state_machine.applied_index().unwrap();
+    assert!(applied_index > index);
+    applied_index
+}
+
+#[test]
+fn restart_membership_change_test() {
+    let n = 5;
+    let leader_id = 1;
+    let dirs: Vec<_> = (0..n).map(|_| TempDir::new().unwrap()).collect();
+    let state_machines: Vec<_> = (0..n).map(|_| MemStateMachine::default()).collect();
+
+    let mut network = VirtualNetwork::new(n);
+    let mut raft_stores = Vec::new();
+    let mut pollers = HashMap::new();
+    for node in 1..n + 1 {
+        let i = (node - 1) as usize;
+        let network_inbound = network.network_inbounds.remove(&node).unwrap();
+        let network_outbound = network.network_outbounds.remove(&node).unwrap();
+        let (raft_store, poller) = start_on_disk(
+            &network, node, dirs[i].path(), &state_machines[i], (network_inbound, network_outbound), &RaftConfig::default(), node == leader_id);
+        raft_stores.push(raft_store);
+        pollers.insert(node, poller);
+    }
+    network.async_start();
+
+    // the cluster starts with the nodes 1, 2 and 3, the nodes 4 and 5 can't be reached yet
+    network.isolate(4);
+    network.isolate(5);
+    for node in 2..4 {
+        let mut conf_change = ConfChange::default();
+        conf_change.node_id = node;
+        conf_change.set_change_type(ConfChangeType::AddNode);
+        let (proposal, rx) = Proposal::conf_change(&conf_change);
+        network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
+        assert!(rx.recv().unwrap().is_ok());
+    }
+    for i in 1..4 {
+        assert!(raft_stores[(leader_id - 1) as usize].propose(insert_request(i)).is_ok());
+    }
+
+    // replace node 2 by nodes 4 and 5. The change can't be finalized while they are isolated,
+    // so node 3 stays in the joint configuration once it applied the begin entry
+    let restarted = 3;
+    let i = (restarted - 1) as usize;
+    wait_applied(&state_machines[i], 3);
+    let applied_index = state_machines[i].applied_index().unwrap();
+    let mut cc = ConfChange::default();
+    cc.set_change_type(ConfChangeType::BeginMembershipChange);
+    cc.mut_configuration().nodes = vec![1, 3, 4, 5];
+    let (proposal, rx) = Proposal::conf_change(&cc);
+    network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
+    wait_applied_after(&state_machines[i], applied_index);
+
+    // node 3 restarts in the middle of the change, it must still know the configuration after it
+    let poller = pollers.remove(&restarted).unwrap();
+    poller.stop();
+    poller.join();
+    let (network_inbound, network_outbound) = network.reconnect(restarted);
+    let (raft_store, poller) = start_on_disk(
+        &network, restarted, dirs[i].path(), &state_machines[i], (network_inbound, network_outbound), &RaftConfig::default(), false);
+    raft_stores[i] = raft_store;
+    pollers.insert(restarted, poller);
+
+    network.heal(4);
+    network.heal(5);
+    assert!(rx.recv().unwrap().is_ok());
+
+    // as the leader, node 3 replicates to the new voters
+    assert!(raft_stores[(leader_id - 1) as usize].transfer_leader(restarted).is_ok());
+    assert!(raft_stores[i].propose(insert_request(4)).is_ok());
+    for node in [3, 4, 5] {
+        wait_applied(&state_machines[node - 1], 4);
+    }
+
+    network.stop();
+    for poller in pollers.values() {
+        poller.stop();
+    }
+}
+
+#[test]
+fn compaction_test() {
+    let n = 3;
+    let leader_id = 1;
+    let config = RaftConfig {
+        compaction: CompactionConfig { snapshot_entries: 5, snapshot_bytes: 0 },
+        ..RaftConfig::default()
+    };
+    let dirs: Vec<_> = (0..n).map(|_| TempDir::new().unwrap()).collect();
+    let state_machines: Vec<_> = (0..n).map(|_| MemStateMachine::default()).collect();
+
+    let mut network = VirtualNetwork::new(n);
+    let mut raft_stores = Vec::new();
+    let mut pollers = HashMap::new();
+    for node in 1..n + 1 {
+        let i = (node - 1) as usize;
+        let network_inbound = network.network_inbounds.remove(&node).unwrap();
+        let network_outbound = network.network_outbounds.remove(&node).unwrap();
+        let (raft_store, poller) = start_on_disk(
+            &network, node, dirs[i].path(), &state_machines[i], (network_inbound, network_outbound), &config, node == leader_id);
+        raft_stores.push(raft_store);
+        pollers.insert(node, poller);
+    }
+    network.async_start();
+
+    for node in 2..n + 1 {
+        let mut conf_change = ConfChange::default();
+        conf_change.node_id = node;
+        conf_change.set_change_type(ConfChangeType::AddNode);
+        let (proposal, rx) = Proposal::conf_change(&conf_change);
+        network.proposal_queues.get_mut(&leader_id).unwrap().add_proposal(proposal);
+        assert!(rx.recv().unwrap().is_ok());
+    }
+    for i in 1..4 {
+        assert!(raft_stores[(leader_id - 1) as usize].propose(insert_request(i)).is_ok());
+    }
+
+    // the follower is down while the leader compacts the log behind it
+    let follower = 3;
+    let i = (follower - 1) as usize;
+    wait_applied(&state_machines[i], 3);
+    let follower_index = state_machines[i].applied_index().unwrap();
+    let poller = pollers.remove(&follower).unwrap();
+    poller.stop();
+    poller.join();
+    for i in 4..21 {
+        assert!(raft_stores[(leader_id - 1) as usize].propose(insert_request(i)).is_ok());
+    }
+
+    // the log is compacted once the leader handled the snapshot of its state machine
+    let leader = &raft_stores[(leader_id - 1) as usize];
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while leader.log_metrics().snapshot_index <= follower_index && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let metrics = leader.log_metrics();
+    assert!(metrics.snapshot_index > follower_index);
+    assert!(metrics.entries_after < metrics.entries_before);
+
+    // the entries the follower misses are gone, it catches up from the leader's snapshot
+    let (network_inbound, network_outbound) = network.reconnect(follower);
+    let (raft_store, poller) = start_on_disk(
+        &network, follower, dirs[i].path(), &state_machines[i], (network_inbound, network_outbound), &config, false);
+    raft_stores[i] = raft_store;
+    pollers.insert(follower, poller);
+    wait_applied(&state_machines[i], 20);
+    assert!(raft_stores[(leader_id - 1) as usize].propose(insert_request(21)).is_ok());
+    for state_machine in &state_machines {
+        wait_applied(state_machine, 21);
+    }
+
+    network.stop();
+    for poller in pollers.values() {
+        poller.stop();
+    }
+}
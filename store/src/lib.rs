@@ -1,7 +1,55 @@
+mod state_machine;
+pub use crate::state_machine::*;
+mod store;
+pub use crate::store::*;
+
 use command::{Response, QueryRequest, Rows, ExecuteRequest};
+pub use raftstore::ProposalError;
+pub use db::{Error as DbError, SqliteError};
 
 #[derive(thiserror::Error, Debug)]
-pub enum Error {}
+pub enum Error {
+    // the node is not the leader. leader_id is the leader known by the node, 0 during an election
+    #[error("node is not the leader, leader is {leader_id}")]
+    NotLeader { leader_id: u64 },
+    // the request wasn't committed in time, it may still be applied later
+    #[error("request timed out")]
+    Timeout,
+    // the request is malformed or refused before reaching the Raft log
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    // the node with the given ID isn't part of the cluster
+    #[error("unknown node: {0}")]
+    NodeNotFound(String),
+    // the node is stopping and doesn't serve requests anymore
+    #[error("node is shutting down")]
+    ShuttingDown,
+    // no leader can take the request right now, e.g. during an election or a leader transfer.
+    // The request may be retried
+    #[error("cluster unavailable: {0}")]
+    Unavailable(String),
+    // the local database failed the request
+    #[error("database error: {0}")]
+    Db(#[from] db::Error),
+    // the request was committed but the state machine couldn't apply it
+    #[error("cannot apply request: {0}")]
+    Apply(String),
+}
+
+impl From<ProposalError> for Error {
+    fn from(err: ProposalError) -> Self {
+        match err {
+            ProposalError::NotLeader { leader_id } => Error::NotLeader { leader_id },
+            ProposalError::Timeout => Error::Timeout,
+            ProposalError::Invalid(message) => Error::InvalidRequest(message),
+            ProposalError::Stopped => Error::ShuttingDown,
+            ProposalError::LeadershipLost | ProposalError::Dropped | ProposalError::Forward(_) => {
+                Error::Unavailable(err.to_string())
+            }
+            ProposalError::Apply(message) => Error::Apply(message),
+        }
+    }
+}
 
 // Database is the interface any queryable system must implement
 pub trait Database {
@@ -11,7 +59,8 @@ pub trait Database {
     fn execute(&mut self, req: ExecuteRequest) -> Result<Vec<Response>, Error>;
 
     // Query executes a slice of queries, each of which returns rows.
-    fn query(&self, req: QueryRequest) -> Result<Rows<'static>, Error>;
+    // Errors of a single query are reported in the error field of its Rows.
+    fn query(&self, req: QueryRequest) -> Result<Vec<Rows>, Error>;
 }
 
 // RaftControl is the interface the Raft-based database must implement.
@@ -25,4 +74,12 @@ pub trait RaftControl {
 
     // leader returns the Raft address of the leader of the cluster.
     fn leader_id(&self) -> Result<String, Error>;
+
+    // change_membership replaces the voters and the learners of the cluster in one step, e.g. to swap
+    // several nodes at once. New nodes must be reachable, i.e. joined before. It returns once the change is finalized.
+    fn change_membership(&mut self, voters: Vec<String>, learners: Vec<String>) -> Result<(), Error>;
+
+    // transfer_leader hands the leadership over to the node with the given ID.
+    // It returns once the node is the leader, so this node can be drained before maintenance.
+    fn transfer_leader(&mut self, id: String) -> Result<(), Error>;
 }
\ No newline at end of file
@@ -0,0 +1,78 @@
+use std::sync::{Arc, Mutex};
+use command::{Request, Response};
+use db::DB;
+use raftstore::StateMachine;
+
+// DbStateMachine applies committed requests to the SQLite database.
+// The database is shared with the read path, which queries it directly.
+pub struct DbStateMachine {
+    db: Arc<Mutex<DB>>,
+}
+
+impl DbStateMachine {
+    pub fn new(db: Arc<Mutex<DB>>) -> DbStateMachine {
+        DbStateMachine { db }
+    }
+}
+
+impl StateMachine for DbStateMachine {
+    fn apply(&mut self, req: &Request) -> Result<Vec<Response>, String> {
+        self.db.lock().unwrap().execute(req).map_err(|err| err.to_string())
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>, String> {
+        self.db.lock().unwrap().snapshot().map_err(|err| err.to_string())
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<(), String> {
+        self.db.lock().unwrap().restore(data).map_err(|err| err.to_string())
+    }
+
+    fn applied_index(&self) -> Result<u64, String> {
+        self.db.lock().unwrap().applied_index().map_err(|err| err.to_string())
+    }
+
+    fn set_applied_index(&mut self, index: u64) -> Result<(), String> {
+        self.db.lock().unwrap().set_applied_index(index).map_err(|err| err.to_string())
+    }
+
+    // the requests of the entry and its index are written in a single SQLite transaction
+    fn apply_entry(&mut self, index: u64, requests: &[Request]) -> Result<Vec<Result<Vec<Response>, String>>, String> {
+        let responses = self.db.lock().unwrap().apply(index, requests).map_err(|err| err.to_string())?;
+        Ok(responses.into_iter().map(Ok).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{Parameters, Statement};
+
+    fn request(sql: &str) -> Request {
+        Request {
+            transaction: false,
+            statements: Box::new([Statement { sql: sql.to_string(), parameters: Parameters::default() }]),
+        }
+    }
+
+    #[test]
+    fn test_apply_snapshot_restore() {
+        let db = Arc::new(Mutex::new(DB::open_in_memory().unwrap()));
+        let mut sm = DbStateMachine::new(db.clone());
+
+        assert!(sm.apply(&request("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, name TEXT)")).is_ok());
+        let r = sm.apply(&request(r#"INSERT INTO foo(name) VALUES("fiona")"#)).unwrap();
+        assert_eq!(r[0].last_insert_id, 1);
+
+        let snapshot = sm.snapshot().unwrap();
+        let mut other = DbStateMachine::new(Arc::new(Mutex::new(DB::open_in_memory().unwrap())));
+        assert!(other.restore(&snapshot).is_ok());
+
+        let db = other.db.lock().unwrap();
+        let r = db.query_string_stmt("SELECT name FROM foo").unwrap();
+        assert_eq!(
+            r#"[{"columns":["name"],"types":["text"],"values":[["fiona"]]}]"#,
+            serde_json::to_string(&r).unwrap()
+        );
+    }
+}
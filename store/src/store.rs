@@ -0,0 +1,235 @@
+use std::sync::{Arc, Mutex};
+use command::{ConsistencyLevel, Response, QueryRequest, Rows, ExecuteRequest};
+use db::DB;
+use raftstore::RaftStore;
+use crate::{Database, Error, RaftControl};
+
+// Store is the Raft-based database: writes go through the Raft log, reads query the local SQLite database.
+// The database is shared with the DbStateMachine of the node.
+pub struct Store {
+    raft_store: RaftStore,
+    db: Arc<Mutex<DB>>,
+}
+
+impl Store {
+    pub fn new(raft_store: RaftStore, db: Arc<Mutex<DB>>) -> Store {
+        Store { raft_store, db }
+    }
+
+    fn check_leader(&self) -> Result<(), Error> {
+        if !self.raft_store.is_leader() {
+            let leader_id = self.raft_store.leader_id();
+            return Err(Error::NotLeader { leader_id });
+        }
+        Ok(())
+    }
+}
+
+// node IDs are the Raft IDs of the nodes, in decimal
+fn parse_id(id: &str) -> Result<u64, Error> {
+    match id.parse::<u64>() {
+        Ok(id) if id != 0 => Ok(id),
+        _ => Err(Error::InvalidRequest(format!("invalid node id: {}", id))),
+    }
+}
+
+fn parse_ids(ids: &[String]) -> Result<Vec<u64>, Error> {
+    ids.iter().map(|id| parse_id(id)).collect()
+}
+
+// the membership changes and the leader transfers must be proposed to the leader
+impl RaftControl for Store {
+    // the node must be reachable at addr by the transport of the Raft messages, it isn't registered here
+    fn join(&mut self, id: String, _addr: String) -> Result<(), Error> {
+        let id = parse_id(&id)?;
+        self.raft_store.add_node(id)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, id: String) -> Result<(), Error> {
+        let id = parse_id(&id)?;
+        self.raft_store.remove_node(id)?;
+        Ok(())
+    }
+
+    fn leader_id(&self) -> Result<String, Error> {
+        match self.raft_store.leader_id() {
+            0 => Err(Error::Unavailable(String::from("no leader is elected"))),
+            leader_id => Ok(leader_id.to_string()),
+        }
+    }
+
+    fn change_membership(&mut self, voters: Vec<String>, learners: Vec<String>) -> Result<(), Error> {
+        let voters = parse_ids(&voters)?;
+        let learners = parse_ids(&learners)?;
+        self.raft_store.change_membership(voters, learners)?;
+        Ok(())
+    }
+
+    fn transfer_leader(&mut self, id: String) -> Result<(), Error> {
+        let id = parse_id(&id)?;
+        self.raft_store.transfer_leader(id)?;
+        Ok(())
+    }
+}
+
+impl Database for Store {
+    fn execute(&mut self, req: ExecuteRequest) -> Result<Vec<Response>, Error> {
+        Ok(self.raft_store.propose(req.request)?)
+    }
+
+    // the local database is queried once the consistency level of the request is satisfied
+    fn query(&self, req: QueryRequest) -> Result<Vec<Rows>, Error> {
+        match req.level {
+            ConsistencyLevel::None => {}
+            ConsistencyLevel::Weak => self.check_leader()?,
+            ConsistencyLevel::Strong => {
+                self.check_leader()?;
+                self.raft_store.barrier()?;
+            }
+            ConsistencyLevel::Linearizable => self.raft_store.read_index()?,
+        }
+        self.db.lock().unwrap().query(&req.request).map_err(Error::Db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{Parameters, Request, Statement};
+    use raftstore::{start, start_for_testing, RaftConfig, VirtualNetwork};
+    use crate::DbStateMachine;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn request(sql: &str) -> Request {
+        Request {
+            transaction: false,
+            statements: Box::new([Statement { sql: sql.to_string(), parameters: Parameters::default() }]),
+        }
+    }
+
+    #[test]
+    fn test_execute_query() {
+        let mut network = VirtualNetwork::new(1);
+        let db = Arc::new(Mutex::new(DB::open_in_memory().unwrap()));
+        let (raft_store, poller) = start_for_testing(
+            1,
+            DbStateMachine::new(db.clone()),
+            network.network_inbounds.remove(&1).unwrap(),
+            network.network_outbounds.remove(&1).unwrap(),
+            network.proposal_queues.get(&1).unwrap().clone(),
+            &RaftConfig::default(),
+            true);
+        network.async_start();
+
+        // requests wait for the node to be elected
+        let mut store = Store::new(raft_store, db);
+        let create = request("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, name TEXT)");
+        assert!(store.execute(ExecuteRequest { request: create }).is_ok());
+        let insert = request(r#"INSERT INTO foo(name) VALUES("fiona")"#);
+        assert_eq!(store.execute(ExecuteRequest { request: insert }).unwrap()[0].last_insert_id, 1);
+
+        let levels = vec![
+            ConsistencyLevel::None, ConsistencyLevel::Weak, ConsistencyLevel::Strong, ConsistencyLevel::Linearizable,
+        ];
+        for level in levels {
+            let rows = store.query(QueryRequest { request: request("SELECT name FROM foo"), level }).unwrap();
+            assert_eq!(
+                r#"[{"columns":["name"],"types":["text"],"values":[["fiona"]]}]"#,
+                serde_json::to_string(&rows).unwrap()
+            );
+        }
+
+        network.stop();
+        poller.stop();
+    }
+
+    #[test]
+    fn test_raft_control() {
+        let n = 3;
+        let mut network = VirtualNetwork::new(n);
+        let mut stores = Vec::new();
+        let mut pollers = Vec::new();
+        for node in 1..n + 1 {
+            let db = Arc::new(Mutex::new(DB::open_in_memory().unwrap()));
+            let (raft_store, poller) = start_for_testing(
+                node,
+                DbStateMachine::new(db.clone()),
+                network.network_inbounds.remove(&node).unwrap(),
+                network.network_outbounds.remove(&node).unwrap(),
+                network.proposal_queues.get(&node).unwrap().clone(),
+                &RaftConfig::default(),
+                node == 1);
+            stores.push(Store::new(raft_store, db));
+            pollers.push(poller);
+        }
+        network.async_start();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while stores[0].leader_id().is_err() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(stores[0].leader_id().unwrap(), "1");
+
+        assert!(matches!(stores[0].join("node-2".to_string(), String::new()), Err(Error::InvalidRequest(_))));
+        assert!(stores[0].join("2".to_string(), String::new()).is_ok());
+        assert!(stores[0].join("3".to_string(), String::new()).is_ok());
+
+        assert!(stores[0].transfer_leader("2".to_string()).is_ok());
+        assert_eq!(stores[1].leader_id().unwrap(), "2");
+        assert!(matches!(stores[0].remove("3".to_string()), Err(Error::NotLeader { leader_id: 2 })));
+
+        // node 3 leaves the cluster in one step, then node 1 is removed
+        assert!(stores[1].change_membership(vec!["1".to_string(), "2".to_string()], vec![]).is_ok());
+        assert!(stores[1].remove("1".to_string()).is_ok());
+
+        network.stop();
+        for poller in pollers {
+            poller.stop();
+        }
+    }
+
+    #[test]
+    fn test_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("dust.db");
+        let raft_dir = dir.path().join("raft");
+        let mut network = VirtualNetwork::new(1);
+        let proposal_queue = network.proposal_queues.get(&1).unwrap().clone();
+        let start_node = |network_inbound, network_outbound| {
+            let db = Arc::new(Mutex::new(DB::open(db_path.to_str().unwrap()).unwrap()));
+            let (raft_store, poller) = start(
+                1, &raft_dir, DbStateMachine::new(db.clone()), &RaftConfig::default(),
+                network_inbound, network_outbound, proposal_queue.clone(), true).unwrap();
+            (Store::new(raft_store, db), poller)
+        };
+
+        let (mut store, poller) = start_node(
+            network.network_inbounds.remove(&1).unwrap(), network.network_outbounds.remove(&1).unwrap());
+        network.async_start();
+        let create = request("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, name TEXT)");
+        assert!(store.execute(ExecuteRequest { request: create }).is_ok());
+        for _ in 0..3 {
+            let insert = request(r#"INSERT INTO foo(name) VALUES("fiona")"#);
+            assert!(store.execute(ExecuteRequest { request: insert }).is_ok());
+        }
+        poller.stop();
+        poller.join();
+        drop(store);
+
+        // the entries applied before the restart aren't applied again, the rows aren't duplicated
+        let (network_inbound, network_outbound) = network.reconnect(1);
+        let (mut store, poller) = start_node(network_inbound, network_outbound);
+        let insert = request(r#"INSERT INTO foo(name) VALUES("aoife")"#);
+        assert_eq!(store.execute(ExecuteRequest { request: insert }).unwrap()[0].last_insert_id, 4);
+        let rows = store.query(QueryRequest { request: request("SELECT count(*) FROM foo"), level: ConsistencyLevel::Strong }).unwrap();
+        assert_eq!(
+            r#"[{"columns":["count(*)"],"types":["integer"],"values":[[4]]}]"#,
+            serde_json::to_string(&rows).unwrap()
+        );
+
+        network.stop();
+        poller.stop();
+    }
+}